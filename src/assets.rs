@@ -1,148 +1,636 @@
-use asefile::AsepriteFile;
-use hashmap_macro::hashmap;
-use image::EncodableLayout;
+use std::{collections::HashMap, fmt, ops::Range, rc::Rc};
+
+use asefile::{AnimationDirection, AsepriteFile, BlendMode, LayerType};
+use image::{Rgba, RgbaImage};
 use macroquad::prelude::*;
 
-use crate::utils::*;
+use crate::{
+    atlas::{self, Sprite},
+    dialogue::Conversations,
+    entities::{Entity, get_entities},
+    files::*,
+    locale::Locale,
+    text::RichText,
+    tiled::*,
+    utils::*,
+};
 
 pub struct Assets {
     pub tileset: Spritesheet,
-    pub gift_selection_screen: Texture2D,
-    pub win_screen: Texture2D,
-    pub start_button: Texture2D,
-    pub start_button_hovered: Texture2D,
+    pub gift_selection_screen: Sprite,
+    pub win_screen: Sprite,
+    pub start_button: Sprite,
+    pub start_button_hovered: Sprite,
     pub gift_sprites: Animation,
-    pub arrow: Texture2D,
-    pub arrow_hovered: Texture2D,
-    pub font: Spritesheet,
+    pub arrow: Sprite,
+    pub arrow_hovered: Sprite,
+    pub font: Font,
+    pub tooltip_panel: Panel,
+    pub bubble_panel: Panel,
+    pub locale: Locale,
+    pub conversations: Conversations,
+    /// Recolours the whole screen, for colour blind players. See `palette_setting`.
+    pub palette: Option<Rc<PaletteSwap>>,
+}
+impl Assets {
+    /// Loads everything, with the strings for `language`
+    pub fn load(language: &str) -> Result<Self, AssetError> {
+        Ok(Self {
+            tileset: Spritesheet::new(load_ase_sprite("tileset.ase", None)?, 8.0),
+            gift_selection_screen: load_ase_sprite("gift_selection_screen.ase", None)?,
+            win_screen: load_ase_sprite("win_screen.ase", None)?,
+            arrow: load_ase_sprite("arrow.ase", Some("normal"))?,
+            arrow_hovered: load_ase_sprite("arrow.ase", Some("hovered"))?,
+            start_button: load_ase_sprite("start_button.ase", Some("normal"))?,
+            start_button_hovered: load_ase_sprite("start_button.ase", Some("hovered"))?,
+            gift_sprites: Animation::from_file("gifts.ase")?,
+            font: Font::from_files("font.ase", "font.txt")?,
+            tooltip_panel: Panel::from_file("ui/tooltip.ase")?,
+            bubble_panel: Panel::from_file("ui/bubble.ase")?,
+            locale: Locale::load(language),
+            conversations: Conversations::load(),
+            palette: palette_setting().and_then(|f| load_palette(&f)),
+        })
+    }
+    /// Draws text with its top left at `x` and `y`, wrapped to `max_width`
+    pub fn draw_text(&self, text: &RichText, x: f32, y: f32, max_width: f32) {
+        self.draw_text_revealed(text, x, y, max_width, text.text.len());
+    }
+    /// Same as `draw_text`, but only draws the first `revealed` bytes of the text.
+    /// The rest still takes up space, so the text doesn't rewrap as it's revealed.
+    pub fn draw_text_revealed(
+        &self,
+        text: &RichText,
+        x: f32,
+        y: f32,
+        max_width: f32,
+        revealed: usize,
+    ) {
+        let time = get_time();
+        let mut color = TEXT_COLOR;
+        gl_use_material(&COLOR_MOD_MATERIAL);
+        COLOR_MOD_MATERIAL.set_uniform("color", color);
+        self.font.layout(&text.text, max_width, |index, char, pos| {
+            if index >= revealed {
+                return;
+            }
+            let style = text.style_at(index);
+            let style_color = style.color.unwrap_or(TEXT_COLOR);
+            if style_color != color {
+                color = style_color;
+                COLOR_MOD_MATERIAL.set_uniform("color", color);
+            }
+            let offset = style.motion.map_or(Vec2::ZERO, |f| f.offset(pos.x, time));
+            self.font
+                .draw_glyph(char, x + pos.x + offset.x, y + pos.y + offset.y);
+        });
+        gl_use_default_material();
+    }
+    /// Size of the box `draw_text` fills
+    pub fn measure_text(&self, text: &str, max_width: f32) -> Vec2 {
+        self.font.layout(text, max_width, |_, _, _| {})
+    }
+}
+
+/// Name of the palette in `palettes/` to draw the whole screen with, like `deuteranopia`.
+/// Set with the `MAIL_PALETTE` environment variable.
+pub fn palette_setting() -> Option<String> {
+    std::env::var("MAIL_PALETTE").ok().filter(|f| !f.is_empty())
+}
+
+/// Loads `palettes/<name>.ase`. If it can't be, that's reported and things are drawn
+/// in their own colours instead.
+pub fn load_palette(name: &str) -> Option<Rc<PaletteSwap>> {
+    PaletteSwap::from_file(&format!("palettes/{name}.ase"))
+        .inspect_err(|err| eprintln!("can't load palette {name:?}: {err}"))
+        .ok()
+        .map(Rc::new)
+}
+
+/// An asset file that couldn't be loaded
+#[derive(Debug)]
+pub struct AssetError {
+    pub path: String,
+    pub reason: String,
 }
-impl Default for Assets {
-    fn default() -> Self {
+impl AssetError {
+    pub fn new(path: &str, reason: impl ToString) -> Self {
         Self {
-            tileset: Spritesheet::new(
-                load_ase_texture(include_bytes!("../assets/tileset.ase"), None),
-                8.0,
-            ),
-            gift_selection_screen: load_ase_texture(
-                include_bytes!("../assets/gift_selection_screen.ase"),
-                None,
-            ),
-            win_screen: load_ase_texture(include_bytes!("../assets/win_screen.ase"), None),
-            arrow: load_ase_texture(include_bytes!("../assets/arrow.ase"), None),
-            arrow_hovered: load_ase_texture(include_bytes!("../assets/arrow_hovered.ase"), None),
-            start_button: load_ase_texture(include_bytes!("../assets/start_button.ase"), None),
-            start_button_hovered: load_ase_texture(
-                include_bytes!("../assets/start_button_hovered.ase"),
-                None,
-            ),
-            gift_sprites: Animation::from_file(include_bytes!("../assets/gifts.ase")),
-            font: Spritesheet::new(
-                load_ase_texture(include_bytes!("../assets/font.ase"), None),
-                4.0,
-            ),
+            path: path.to_string(),
+            reason: reason.to_string(),
         }
     }
 }
-impl Assets {
-    pub fn draw_text(&self, text: &str, mut x: f32, mut y: f32) -> (f32, f32) {
-        let original_x = x;
-        let original_y = y;
-        let hardcoded = hashmap!(':'=>36,'.'=>37,'-'=>38,'%'=>39,'+'=>40,'/'=>41,'H'=>42,'('=>43,')'=>44,'!'=>45,'?'=>46);
-        gl_use_material(&COLOR_MOD_MATERIAL);
-        COLOR_MOD_MATERIAL.set_uniform("color", TEXT_COLORS[1]);
-        let mut start_of_line = true;
-
-        for char in text.chars() {
-            if char == '\n' {
-                start_of_line = true;
-                y += 5.0;
-                x = original_x;
-                continue;
-            } else if char == ' ' {
-                if start_of_line {
-                    continue;
-                }
-                x += 4.0;
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.path, self.reason)
+    }
+}
+impl std::error::Error for AssetError {}
+
+/// Contents of an asset file, or an error if there's no such file
+pub fn read_asset(path: &str) -> Result<Vec<u8>, AssetError> {
+    file(path).ok_or_else(|| AssetError::new(path, "no such file"))
+}
+fn read_ase(path: &str) -> Result<AsepriteFile, AssetError> {
+    AsepriteFile::read(&*read_asset(path)?).map_err(|err| AssetError::new(path, err))
+}
+
+/// Loads the first frame of an aseprite file. Uses only the named layer if there is one
+/// (even if it's hidden), otherwise every visible layer that isn't part of a `Pass`.
+fn load_ase_sprite(path: &str, layer: Option<&str>) -> Result<Sprite, AssetError> {
+    let ase = read_ase(path)?;
+    let image = match layer {
+        Some(name) => {
+            if ase.layer_by_name(name).is_none() {
+                return Err(AssetError::new(path, format!("no layer {name:?}")));
+            }
+            composite_layers(&ase, 0, |f| f.name() == name)
+        }
+        None => main_layers(&ase, 0),
+    };
+    Ok(atlas::add(&image))
+}
+/// Combines the visible layers of a frame that aren't part of a `Pass`
+fn main_layers(ase: &AsepriteFile, frame: u32) -> RgbaImage {
+    composite_layers(ase, frame, |f| {
+        f.is_visible() && Pass::of_layer(f.name()).is_none()
+    })
+}
+
+/// Parts of a sprite that are drawn separately from the rest of it.
+/// Layers are part of a pass when their name starts with the pass' name, like `emissive windows`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// Added onto whatever is drawn under it, so it glows
+    Emissive,
+    /// Drawn under the sprite
+    Shadow,
+    /// Drawn over the sprite
+    Overlay,
+}
+impl Pass {
+    const ALL: [Pass; 3] = [Pass::Emissive, Pass::Shadow, Pass::Overlay];
+
+    fn of_layer(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        Self::ALL.into_iter().find(|f| {
+            name.starts_with(match f {
+                Pass::Emissive => "emissive",
+                Pass::Shadow => "shadow",
+                Pass::Overlay => "overlay",
+            })
+        })
+    }
+}
+
+/// Combines some of the layers of a frame, using their opacity and blend mode
+fn composite_layers(
+    ase: &AsepriteFile,
+    frame: u32,
+    include: impl Fn(&asefile::Layer) -> bool,
+) -> RgbaImage {
+    let mut image = RgbaImage::new(ase.width() as u32, ase.height() as u32);
+    // layers are ordered from the bottom up
+    for layer in ase.layers() {
+        if layer.layer_type() == LayerType::Group || !include(&layer) {
+            continue;
+        }
+        let blend_mode = layer.blend_mode();
+        // the cel's image already has the layer's opacity applied
+        let cel = layer.frame(frame).image();
+        for (below, above) in image.pixels_mut().zip(cel.pixels()) {
+            *below = blend_pixel(*below, *above, blend_mode);
+        }
+    }
+    image
+}
+
+/// Draws `src` over `dst`. Blend modes that aren't supported are treated as normal.
+fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>, mode: BlendMode) -> Rgba<u8> {
+    if src[3] == 0 {
+        return dst;
+    }
+    let src_alpha = src[3] as f32 / 255.0;
+    let dst_alpha = dst[3] as f32 / 255.0;
+    let alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+    let mut out = Rgba([0, 0, 0, (alpha * 255.0).round() as u8]);
+    for i in 0..3 {
+        let s = src[i] as f32 / 255.0;
+        let d = dst[i] as f32 / 255.0;
+        let blended = match mode {
+            BlendMode::Multiply => s * d,
+            BlendMode::Screen => s + d - s * d,
+            BlendMode::Darken => s.min(d),
+            BlendMode::Lighten => s.max(d),
+            BlendMode::Difference => (s - d).abs(),
+            BlendMode::Addition => (s + d).min(1.0),
+            BlendMode::Subtract => (d - s).max(0.0),
+            _ => s,
+        };
+        // blend modes only apply where there's something below
+        let s = s * (1.0 - dst_alpha) + blended * dst_alpha;
+        let c = (s * src_alpha + d * dst_alpha * (1.0 - src_alpha)) / alpha;
+        out[i] = (c * 255.0).round() as u8;
+    }
+    out
+}
+
+/// A bitmap font. The glyph map says which character is in each cell of the sheet,
+/// see `assets/font.txt`.
+pub struct Font {
+    sprite: Sprite,
+    cell: Vec2,
+    glyphs: HashMap<char, Glyph>,
+    /// How much closer together (or further apart) pairs of characters are drawn
+    kerning: HashMap<(char, char), f32>,
+}
+/// A line of wrapped text
+pub struct Line {
+    /// Where the line is in the text, in bytes
+    pub range: Range<usize>,
+    pub width: f32,
+}
+struct Glyph {
+    /// Top left of the glyph in the sheet, skipping blank columns on the left of its cell
+    source: Vec2,
+    width: f32,
+}
+impl Font {
+    /// Drawn in place of characters the font doesn't have
+    const FALLBACK: char = '\u{fffd}';
+    /// Space between glyphs
+    const SPACING: f32 = 1.0;
+    const LINE_HEIGHT: f32 = 5.0;
+
+    /// Loads the font from an aseprite sheet and a glyph map
+    pub fn from_files(sheet: &str, glyph_map: &str) -> Result<Self, AssetError> {
+        let img = main_layers(&read_ase(sheet)?, 0);
+        let error = |reason: String| AssetError::new(glyph_map, reason);
+        let path = glyph_map;
+        let glyph_map = String::from_utf8_lossy(&read_asset(path)?).into_owned();
+        let mut cell = None;
+        let mut cells = Vec::new();
+        let mut widths = Vec::new();
+        let mut kerning = HashMap::new();
+        let mut row = 0;
+        for (index, line) in glyph_map.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
-            let code = char as u8;
-            if code < TEXT_COLORS.len() as u8 {
-                COLOR_MOD_MATERIAL.set_uniform("color", TEXT_COLORS[code as usize]);
+            // a typo shouldn't lose the whole font, so bad lines are only reported
+            let bad_line = || eprintln!("{path:?} line {}: can't read {line:?}", index + 1);
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "cell" => {
+                    let size = value
+                        .split_whitespace()
+                        .map(|f| f.parse::<f32>().ok())
+                        .collect::<Option<Vec<_>>>();
+                    let Some(&[w, h]) = size.as_deref() else {
+                        bad_line();
+                        continue;
+                    };
+                    cell = Some(Vec2::new(w, h));
+                }
+                "row" => {
+                    for (column, char) in value.chars().enumerate() {
+                        cells.push((char, column as u32, row));
+                    }
+                    row += 1;
+                }
+                "width" => {
+                    let Some((chars, Ok(width))) =
+                        value.rsplit_once(' ').map(|f| (f.0, f.1.parse::<f32>()))
+                    else {
+                        bad_line();
+                        continue;
+                    };
+                    widths.extend(chars.chars().map(|f| (f, width)));
+                }
+                "kern" => {
+                    let Some((pair, Ok(amount))) =
+                        value.rsplit_once(' ').map(|f| (f.0, f.1.parse::<f32>()))
+                    else {
+                        bad_line();
+                        continue;
+                    };
+                    let mut pair = pair.chars();
+                    let (Some(a), Some(b), None) = (pair.next(), pair.next(), pair.next()) else {
+                        bad_line();
+                        continue;
+                    };
+                    kerning.insert((a, b), amount);
+                }
+                _ => bad_line(),
             }
+        }
+        let cell = cell.ok_or_else(|| error("no cell size".to_string()))?;
 
-            let index = if let Some(value) = hardcoded.get(&char) {
-                *value
-            } else if code.is_ascii_lowercase() {
-                code - b'a'
-            } else if code.is_ascii_digit() {
-                code - b'0' + 26
-            } else {
-                continue;
+        // glyphs are as wide as the pixels in their cell, unless the glyph map says otherwise
+        let mut glyphs = HashMap::new();
+        for (char, column, row) in cells {
+            let x = column * cell.x as u32;
+            let y = row * cell.y as u32;
+            if x + cell.x as u32 > img.width() || y + cell.y as u32 > img.height() {
+                return Err(error(format!("{char:?} is outside of the sheet")));
+            }
+            let used = (x..x + cell.x as u32)
+                .filter(|x| (y..y + cell.y as u32).any(|y| img.get_pixel(*x, y)[3] != 0));
+            let left = used.clone().min().unwrap_or(x);
+            let right = used.max().map_or(left, |f| f + 1);
+            glyphs.insert(
+                char,
+                Glyph {
+                    source: Vec2::new(left as f32, y as f32),
+                    width: (right - left) as f32,
+                },
+            );
+        }
+        for (char, width) in widths {
+            let Some(glyph) = glyphs.get_mut(&char) else {
+                return Err(error(format!(
+                    "sets the width of {char:?}, which isn't in the sheet"
+                )));
             };
-            start_of_line = false;
-            self.font
-                .draw_sprite(x + 2.0, y + 2.0, index as f32, 0.0, None);
+            glyph.width = width;
+        }
+        if !glyphs.contains_key(&Self::FALLBACK) {
+            return Err(error(format!("no {:?}", Self::FALLBACK)));
+        }
+        Ok(Self {
+            sprite: atlas::add(&img),
+            cell,
+            glyphs,
+            kerning,
+        })
+    }
+    fn glyph(&self, char: char) -> &Glyph {
+        self.glyphs
+            .get(&char)
+            .unwrap_or(&self.glyphs[&Self::FALLBACK])
+    }
+    /// Splits text into lines no wider than `max_width`, breaking at spaces where it can.
+    /// Words longer than a whole line are broken wherever they need to be.
+    pub fn wrap(&self, text: &str, max_width: f32) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for paragraph in text.split('\n') {
+            // the line so far, up to the end of the last word that fit
+            let mut start = offset;
+            let mut end = offset;
+            let mut word_start = offset;
+            for word in paragraph.split(' ') {
+                let word_end = word_start + word.len();
+                loop {
+                    if self.line_width(&text[start..word_end]) <= max_width {
+                        end = word_end;
+                        break;
+                    }
+                    if end > start {
+                        // move the word onto the next line, dropping the space before it
+                        lines.push(self.line(text, start..end));
+                        start = word_start;
+                        end = start;
+                        continue;
+                    }
+                    // the word doesn't fit on a line by itself, so fit as much of it as possible
+                    let split = text[start..word_end]
+                        .char_indices()
+                        .map(|f| start + f.0 + f.1.len_utf8())
+                        .take_while(|f| self.line_width(&text[start..*f]) <= max_width)
+                        .last()
+                        .unwrap_or_else(|| {
+                            start + text[start..].chars().next().unwrap().len_utf8()
+                        });
+                    if split == word_end {
+                        // only happens when not even one character fits, which is shown anyway
+                        end = split;
+                        break;
+                    }
+                    lines.push(self.line(text, start..split));
+                    start = split;
+                    end = split;
+                }
+                word_start = word_end + 1;
+            }
+            lines.push(self.line(text, start..end));
+            offset += paragraph.len() + 1;
+        }
+        lines
+    }
+    fn line(&self, text: &str, range: Range<usize>) -> Line {
+        Line {
+            width: self.line_width(&text[range.clone()]),
+            range,
+        }
+    }
+    fn line_width(&self, line: &str) -> f32 {
+        self.place_line(line, 0.0, &mut |_, _, _| {})
+    }
+    /// Works out where each character of `text` goes, relative to the top left of the text,
+    /// wrapping it to `max_width`. `place` is called with where each character is in `text`
+    /// (in bytes) and on screen, except for line breaks and other control characters.
+    /// Returns the size of the text.
+    pub fn layout(
+        &self,
+        text: &str,
+        max_width: f32,
+        mut place: impl FnMut(usize, char, Vec2),
+    ) -> Vec2 {
+        let lines = self.wrap(text, max_width);
+        for (index, line) in lines.iter().enumerate() {
+            self.place_line(
+                &text[line.range.clone()],
+                index as f32 * Self::LINE_HEIGHT,
+                &mut |f, char, pos| place(line.range.start + f, char, pos),
+            );
+        }
+        Vec2::new(
+            lines.iter().map(|f| f.width).fold(0.0, f32::max),
+            lines.len() as f32 * Self::LINE_HEIGHT,
+        )
+    }
+    /// Places the characters of a single line, and returns its width
+    fn place_line(&self, line: &str, y: f32, place: &mut impl FnMut(usize, char, Vec2)) -> f32 {
+        let mut x = 0.0;
+        let mut width: f32 = 0.0;
+        let mut last = None;
+        for (index, char) in line.char_indices() {
+            if char.is_control() {
+                continue;
+            }
+            if let Some(last) = last {
+                x += self.kerning.get(&(last, char)).copied().unwrap_or(0.0);
+            }
+            place(index, char, Vec2::new(x, y));
+            x += self.glyph(char).width;
+            if char != ' ' {
+                width = width.max(x);
+            }
+            x += Self::SPACING;
+            last = Some(char);
+        }
+        width
+    }
+    /// Draws a character with its top at `y`. Capitals and accents go one pixel above that.
+    pub fn draw_glyph(&self, char: char, x: f32, y: f32) {
+        let glyph = self.glyph(char);
+        self.sprite.draw_ex(
+            x,
+            y - 1.0,
+            WHITE,
+            DrawTextureParams {
+                source: Some(Rect::new(
+                    glyph.source.x,
+                    glyph.source.y,
+                    glyph.width,
+                    self.cell.y,
+                )),
+                ..Default::default()
+            },
+        );
+    }
+}
 
-            x += 4.0
+/// UI art that stretches to any size. The file needs a slice with a 9-slice center,
+/// the corners stay the same size and the edges and center are stretched.
+pub struct Panel {
+    sprite: Sprite,
+    /// Area of the texture the panel uses
+    bounds: Rect,
+    /// The stretched part, relative to `bounds`
+    center: Rect,
+}
+impl Panel {
+    pub fn from_file(path: &str) -> Result<Self, AssetError> {
+        let ase = read_ase(path)?;
+        let (key, center) = ase
+            .slices()
+            .iter()
+            .flat_map(|f| f.keys.first())
+            .find_map(|f| Some((f, f.slice9.as_ref()?)))
+            .ok_or_else(|| AssetError::new(path, "panel has no 9-slice"))?;
+        Ok(Self {
+            sprite: atlas::add(&main_layers(&ase, 0)),
+            bounds: Rect::new(
+                key.origin.0 as f32,
+                key.origin.1 as f32,
+                key.size.0 as f32,
+                key.size.1 as f32,
+            ),
+            center: Rect::new(
+                center.center_x as f32,
+                center.center_y as f32,
+                center.center_width as f32,
+                center.center_height as f32,
+            ),
+        })
+    }
+    pub fn draw(&self, x: f32, y: f32, w: f32, h: f32) {
+        // edges of the three columns and rows, in the texture and on screen
+        let source_x = [0.0, self.center.x, self.center.right(), self.bounds.w];
+        let source_y = [0.0, self.center.y, self.center.bottom(), self.bounds.h];
+        let dest_x = [
+            0.0,
+            self.center.x,
+            w - (self.bounds.w - self.center.right()),
+            w,
+        ];
+        let dest_y = [
+            0.0,
+            self.center.y,
+            h - (self.bounds.h - self.center.bottom()),
+            h,
+        ];
+        for row in 0..3 {
+            for column in 0..3 {
+                let dest_w = dest_x[column + 1] - dest_x[column];
+                let dest_h = dest_y[row + 1] - dest_y[row];
+                if dest_w <= 0.0 || dest_h <= 0.0 {
+                    continue;
+                }
+                self.sprite.draw_ex(
+                    x + dest_x[column],
+                    y + dest_y[row],
+                    WHITE,
+                    DrawTextureParams {
+                        source: Some(Rect::new(
+                            self.bounds.x + source_x[column],
+                            self.bounds.y + source_y[row],
+                            source_x[column + 1] - source_x[column],
+                            source_y[row + 1] - source_y[row],
+                        )),
+                        dest_size: Some(Vec2::new(dest_w, dest_h)),
+                        ..Default::default()
+                    },
+                );
+            }
         }
+    }
+}
 
-        COLOR_MOD_MATERIAL.set_uniform("color", TEXT_COLORS[0]);
+/// Recolours anything drawn with it, by replacing colours from one palette with the colour
+/// at the same index in another. Useful for variants of a sprite, without copying the file.
+pub struct PaletteSwap {
+    /// Two rows of pixels, the colours to replace and what to replace them with
+    lookup: Texture2D,
+    size: usize,
+}
+impl PaletteSwap {
+    /// Loads a palette swap from an aseprite file, where the top row of pixels has the colours
+    /// to replace and the second row has what they become. The palette ends at the first
+    /// transparent pixel on the top row.
+    pub fn from_file(path: &str) -> Result<Self, AssetError> {
+        let img = read_ase(path)?.frame(0).image();
+        let size = (0..img.width())
+            .take_while(|x| img.get_pixel(*x, 0)[3] != 0)
+            .count();
+        if size == 0 {
+            return Err(AssetError::new(path, "palette has no colours"));
+        }
+        if img.height() < 2 {
+            return Err(AssetError::new(path, "palette has no row of new colours"));
+        }
+        let mut bytes = Vec::with_capacity(size * 2 * 4);
+        for y in 0..2 {
+            for x in 0..size {
+                bytes.extend_from_slice(&img.get_pixel(x as u32, y).0);
+            }
+        }
+        let lookup = Texture2D::from_rgba8(size as u16, 2, &bytes);
+        lookup.set_filter(FilterMode::Nearest);
+        Ok(Self { lookup, size })
+    }
+    /// Runs `draw` with the palette swapped
+    pub fn apply(&self, draw: impl FnOnce()) {
+        gl_use_material(&PALETTE_SWAP_MATERIAL);
+        PALETTE_SWAP_MATERIAL.set_texture("palette", self.lookup.clone());
+        PALETTE_SWAP_MATERIAL.set_uniform("palette_size", self.size as f32);
+        draw();
         gl_use_default_material();
-        (x - original_x, y - original_y)
     }
 }
-fn load_ase_texture(bytes: &[u8], layer: Option<u32>) -> Texture2D {
-    let img = AsepriteFile::read(bytes).unwrap();
-    let img = if let Some(layer) = layer {
-        img.layer(layer).frame(0).image()
-    } else {
-        img.frame(0).image()
-    };
-    let new = Image {
-        width: img.width() as u16,
-        height: img.height() as u16,
-        bytes: img.as_bytes().to_vec(),
-    };
-    let texture = Texture2D::from_image(&new);
-    texture.set_filter(FilterMode::Nearest);
-    texture
-}
 
 pub struct Spritesheet {
-    pub texture: Texture2D,
+    pub sprite: Sprite,
     pub sprite_size: f32,
 }
 impl Spritesheet {
-    pub fn new(texture: Texture2D, sprite_size: f32) -> Self {
+    pub fn new(sprite: Sprite, sprite_size: f32) -> Self {
         Self {
-            texture,
+            sprite,
             sprite_size,
         }
     }
-    /// Same as `draw_tile`, except centered
-    pub fn draw_sprite(
-        &self,
-        screen_x: f32,
-        screen_y: f32,
-        tile_x: f32,
-        tile_y: f32,
-        params: Option<&DrawTextureParams>,
-    ) {
-        self.draw_tile(
-            screen_x - self.sprite_size / 2.0,
-            screen_y - self.sprite_size / 2.0,
-            tile_x,
-            tile_y,
-            params,
-        );
-    }
-    /// Draws a single tile from the spritesheet
-    pub fn draw_tile(
+    /// Draws a single tile from the spritesheet, tinted by `color`
+    pub fn draw_tile_ex(
         &self,
         screen_x: f32,
         screen_y: f32,
         tile_x: f32,
         tile_y: f32,
+        color: Color,
         params: Option<&DrawTextureParams>,
     ) {
         let mut p = params.cloned().unwrap_or(DrawTextureParams::default());
@@ -155,190 +643,1037 @@ impl Spritesheet {
             w: self.sprite_size,
             h: self.sprite_size,
         }));
-        draw_texture_ex(&self.texture, screen_x, screen_y, WHITE, p);
+        self.sprite.draw_ex(screen_x, screen_y, color, p);
     }
 }
 
 pub struct Animation {
-    frames: Vec<(Texture2D, u32)>,
+    frames: Vec<(Sprite, u32)>,
+    /// Sprites of each pass the file has, for every frame
+    passes: HashMap<Pass, Vec<Sprite>>,
     pub total_length: u32,
+    /// Frame tags from the file, by name
+    tags: HashMap<String, AnimationTag>,
+    /// Size of a frame
+    pub size: Vec2,
+    /// Areas of the sprite marked with slices, by name
+    slices: HashMap<String, Rect>,
+    /// Point the sprite is drawn around, set as the pivot of any slice
+    pub pivot: Option<Vec2>,
+    /// Recolours everything but the emissive pass
+    pub palette: Option<Rc<PaletteSwap>>,
 }
 impl Animation {
-    pub fn from_file(bytes: &[u8]) -> Self {
-        let ase = AsepriteFile::read(bytes).unwrap();
+    pub fn from_file(path: &str) -> Result<Self, AssetError> {
+        let ase = read_ase(path)?;
         let mut frames = Vec::new();
         let mut total_length = 0;
         for index in 0..ase.num_frames() {
-            let frame = ase.frame(index);
-            let img = frame.image();
-            let new = Image {
-                width: img.width() as u16,
-                height: img.height() as u16,
-                bytes: img.as_bytes().to_vec(),
-            };
-            let duration = frame.duration();
+            let img = main_layers(&ase, index);
+            let duration = ase.frame(index).duration();
             total_length += duration;
-            let texture = Texture2D::from_image(&new);
-            frames.push((texture, duration));
+            frames.push((atlas::add(&img), duration));
         }
-        Self {
+        let mut passes = HashMap::new();
+        for pass in Pass::ALL {
+            let in_pass =
+                |f: &asefile::Layer| f.is_visible() && Pass::of_layer(f.name()) == Some(pass);
+            if !ase.layers().any(|f| in_pass(&f)) {
+                continue;
+            }
+            let sprites = (0..ase.num_frames())
+                .map(|index| atlas::add(&composite_layers(&ase, index, in_pass)))
+                .collect();
+            passes.insert(pass, sprites);
+        }
+        let tags = (0..ase.num_tags())
+            .map(|index| {
+                let tag = ase.tag(index);
+                (tag.name().to_string(), AnimationTag::new(tag, &frames))
+            })
+            .collect();
+        let mut slices = HashMap::new();
+        let mut pivot = None;
+        for slice in ase.slices() {
+            // only the first key is used, slices don't move between frames
+            let Some(key) = slice.keys.first() else {
+                continue;
+            };
+            let origin = Vec2::new(key.origin.0 as f32, key.origin.1 as f32);
+            if let Some((x, y)) = key.pivot {
+                pivot = pivot.or(Some(origin + Vec2::new(x as f32, y as f32)));
+            }
+            let size = Vec2::new(key.size.0 as f32, key.size.1 as f32);
+            slices.insert(
+                slice.name.clone(),
+                Rect::new(origin.x, origin.y, size.x, size.y),
+            );
+        }
+        Ok(Self {
             frames,
+            passes,
             total_length,
-        }
+            tags,
+            size: Vec2::new(ase.width() as f32, ase.height() as f32),
+            slices,
+            pivot,
+            palette: None,
+        })
+    }
+    /// Area of a slice, relative to the top left of the sprite
+    pub fn slice(&self, name: &str) -> Option<Rect> {
+        self.slices.get(name).copied()
+    }
+    /// Frame at a time, looping through every frame in the file
+    pub fn get_at_time(&self, time: u32) -> &Sprite {
+        &self.frames[self.frame_at_time(time)].0
     }
-    pub fn get_at_time(&self, mut time: u32) -> &Texture2D {
+    /// Index of the frame at a time, looping through every frame in the file
+    pub fn frame_at_time(&self, mut time: u32) -> usize {
         time %= self.total_length;
-        for (texture, length) in self.frames.iter() {
+        for (index, (_, length)) in self.frames.iter().enumerate() {
             if time >= *length {
                 time -= length;
             } else {
-                return texture;
+                return index;
             }
         }
         panic!()
     }
+    /// Index of the frame at a time into a tag.
+    /// Tags that don't loop stay on their last frame once finished.
+    pub fn tag_frame_at_time(&self, name: &str, mut time: u32) -> usize {
+        let tag = self.tag(name);
+        if self.is_finished(name, time) {
+            return *tag.frames.last().unwrap();
+        }
+        time %= tag.length;
+        for index in tag.frames.iter() {
+            let length = self.frames[*index].1;
+            if time >= length {
+                time -= length;
+            } else {
+                return *index;
+            }
+        }
+        panic!()
+    }
+    /// Draws a frame with its passes: shadows under it, then overlays and emissive layers over it
+    pub fn draw_frame(&self, index: usize, x: f32, y: f32, params: &DrawTextureParams) {
+        let draw_pass = |pass| {
+            if let Some(sprites) = self.passes.get(&pass) {
+                sprites[index].draw_ex(x, y, WHITE, params.clone());
+            }
+        };
+        let draw = || {
+            draw_pass(Pass::Shadow);
+            self.frames[index].0.draw_ex(x, y, WHITE, params.clone());
+            draw_pass(Pass::Overlay);
+        };
+        match &self.palette {
+            Some(palette) => palette.apply(draw),
+            None => draw(),
+        }
+        // switching materials breaks the batch, so only when there's something to draw
+        if self.passes.contains_key(&Pass::Emissive) {
+            gl_use_material(&ADDITIVE_MATERIAL);
+            draw_pass(Pass::Emissive);
+            gl_use_default_material();
+        }
+    }
+    /// Whether a tag that doesn't loop has played all of its repeats
+    pub fn is_finished(&self, name: &str, time: u32) -> bool {
+        let tag = self.tag(name);
+        tag.repeat.is_some_and(|f| time >= tag.length * f)
+    }
+    pub fn has_tag(&self, name: &str) -> bool {
+        self.tags.contains_key(name)
+    }
+    fn tag(&self, name: &str) -> &AnimationTag {
+        self.tags
+            .get(name)
+            .unwrap_or_else(|| panic!("animation has no tag {name:?}"))
+    }
+}
+
+/// A named range of frames, set up in aseprite
+struct AnimationTag {
+    /// Frame indices in the order they're shown, for one play through
+    frames: Vec<usize>,
+    /// Length of one play through, in milliseconds
+    length: u32,
+    /// How many times to play before stopping, or `None` to loop forever
+    repeat: Option<u32>,
+}
+impl AnimationTag {
+    fn new(tag: &asefile::Tag, frames: &[(Sprite, u32)]) -> Self {
+        let from = tag.from_frame() as usize;
+        let to = tag.to_frame() as usize;
+        let order: Vec<usize> = match tag.animation_direction() {
+            AnimationDirection::Forward => (from..=to).collect(),
+            AnimationDirection::Reverse => (from..=to).rev().collect(),
+            // goes back without showing the first and last frames twice
+            AnimationDirection::PingPong => (from..=to).chain((from + 1..to).rev()).collect(),
+        };
+        Self {
+            length: order.iter().map(|f| frames[*f].1).sum(),
+            frames: order,
+            repeat: tag.repeat().map(|f| f.get()),
+        }
+    }
+}
+
+/// Layers the player collides with, found by name
+#[derive(Clone, Copy)]
+pub enum WorldLayer {
+    Collision,
+    OneWayCollision,
 }
 
 pub struct World {
-    pub collision: Vec<Chunk>,
-    pub one_way_collision: Vec<Chunk>,
-    pub details: Vec<Chunk>,
-    pub background: Vec<Chunk>,
-    pub interactable: Vec<Chunk>,
+    /// Name of the map this was loaded from, its file is `tilemap/<name>.tmx`
+    pub name: String,
+    /// Tile and image layers, in the order they're drawn
+    pub layers: Vec<MapLayer>,
+    /// Indices into `layers` of the `Collision` and `OneWayCollision` layers
+    collision: usize,
+    one_way_collision: usize,
+    /// Where layers with parallax line up with the rest of the map
+    parallax_origin: Vec2,
+    /// Objects from every object layer, with layer offsets applied
+    pub objects: Vec<Object>,
+    /// Behaviour of tiles that set a type in the tileset, by tile id
+    pub tile_kinds: HashMap<u32, TileKind>,
+    pub tile_animations: HashMap<u32, TileAnimation>,
 }
 impl World {
-    pub fn get_interactable_spawn(&self, tile_index: i16) -> Option<Vec2> {
-        for chunk in self.interactable.iter() {
-            for (i, tile) in chunk.tiles.iter().enumerate() {
-                if *tile == tile_index + 1 {
-                    return Some(Vec2::new(
-                        (i as i16 % 16 + chunk.x) as f32 * 8.0,
-                        (i as i16 / 16 + chunk.y) as f32 * 8.0,
-                    ));
+    pub fn load(name: &str) -> Result<Self, MapError> {
+        let path = format!("tilemap/{name}.tmx");
+        let xml = file(&path).ok_or_else(|| MapError::MissingMap(name.to_string()))?;
+        let load_file = |source: &str| file(&relative_to(&path, source));
+        let mut world = World::from_tmx(&String::from_utf8_lossy(&xml), load_file)?;
+        world.name = name.to_string();
+        Ok(world)
+    }
+    /// A map with nothing in it, for when one fails to load. Reloading it tries
+    /// loading `tilemap/<name>.tmx` again.
+    pub fn empty(name: &str) -> Self {
+        let tile_layer = || MapLayer {
+            visible: true,
+            color: WHITE,
+            offset: Vec2::ZERO,
+            parallax: Vec2::ONE,
+            foreground: false,
+            kind: MapLayerKind::Tiles(TileLayer {
+                chunks: HashMap::new(),
+            }),
+        };
+        World {
+            name: name.to_string(),
+            layers: vec![tile_layer(), tile_layer()],
+            collision: 0,
+            one_way_collision: 1,
+            parallax_origin: Vec2::ZERO,
+            objects: Vec::new(),
+            tile_kinds: HashMap::new(),
+            tile_animations: HashMap::new(),
+        }
+    }
+    /// `load_file` is given paths relative to the map, of tilesets and images
+    pub fn from_tmx(
+        xml: &str,
+        load_file: impl Fn(&str) -> Option<Vec<u8>>,
+    ) -> Result<Self, MapError> {
+        let map = Map::parse(xml, |source| {
+            load_file(source).and_then(|f| String::from_utf8(f).ok())
+        })?;
+        if map.tile_width != 8 || map.tile_height != 8 {
+            return Err(MapError::WrongTileSize {
+                width: map.tile_width,
+                height: map.tile_height,
+            });
+        }
+        let empty = HashMap::new();
+        let tiles = map.tilesets.first().map_or(&empty, |f| &f.tileset.tiles);
+
+        let mut layers = Vec::new();
+        let mut collision = None;
+        let mut one_way_collision = None;
+        for layer in map.layers.iter() {
+            let is_role = matches!(layer.name.as_str(), "Collision" | "OneWayCollision");
+            if is_role && !matches!(layer.kind, LayerKind::Tiles(_)) {
+                return Err(MapError::WrongLayerKind {
+                    name: layer.name.clone(),
+                    expected: "tile",
+                });
+            }
+            let kind = match &layer.kind {
+                LayerKind::Tiles(tile_chunks) => {
+                    match layer.name.as_str() {
+                        "Collision" => collision = Some(layers.len()),
+                        "OneWayCollision" => one_way_collision = Some(layers.len()),
+                        _ => {}
+                    }
+                    MapLayerKind::Tiles(TileLayer::from_map(&map, &layer.name, tile_chunks)?)
+                }
+                LayerKind::Image(image) if !image.source.is_empty() => {
+                    let bytes = load_file(&image.source)
+                        .ok_or_else(|| MapError::MissingImage(image.source.clone()))?;
+                    let invalid = |reason: String| MapError::InvalidImage {
+                        source: image.source.clone(),
+                        reason,
+                    };
+                    let sprite =
+                        if image.source.ends_with(".ase") || image.source.ends_with(".aseprite") {
+                            let ase = AsepriteFile::read(&*bytes)
+                                .map_err(|err| invalid(err.to_string()))?;
+                            atlas::add(&main_layers(&ase, 0))
+                        } else {
+                            let decoded = image::load_from_memory(&bytes)
+                                .map_err(|err| invalid(err.to_string()))?;
+                            atlas::add(&decoded.to_rgba8())
+                        };
+                    MapLayerKind::Image {
+                        sprite,
+                        repeat_x: image.repeat_x,
+                        repeat_y: image.repeat_y,
+                    }
+                }
+                _ => continue,
+            };
+            let [r, g, b, a] = layer.tint;
+            layers.push(MapLayer {
+                visible: layer.visible,
+                color: Color::new(r, g, b, a * layer.opacity),
+                offset: Vec2::new(layer.offset_x, layer.offset_y),
+                parallax: Vec2::new(layer.parallax_x, layer.parallax_y),
+                foreground: layer.properties.get("foreground").unwrap_or(false),
+                kind,
+            });
+        }
+        let role_layer = |index: Option<usize>, name: &str| {
+            index.ok_or_else(|| MapError::MissingLayer(name.to_string()))
+        };
+
+        Ok(World {
+            name: String::new(),
+            collision: role_layer(collision, "Collision")?,
+            one_way_collision: role_layer(one_way_collision, "OneWayCollision")?,
+            layers,
+            parallax_origin: Vec2::new(map.parallax_origin_x, map.parallax_origin_y),
+            objects: map
+                .layers
+                .iter()
+                .flat_map(|layer| {
+                    let LayerKind::Objects(objects) = &layer.kind else {
+                        return Vec::new();
+                    };
+                    objects
+                        .iter()
+                        .cloned()
+                        .map(|mut object| {
+                            object.x += layer.offset_x;
+                            object.y += layer.offset_y;
+                            object
+                        })
+                        .collect()
+                })
+                .collect(),
+            tile_kinds: tiles
+                .iter()
+                .filter_map(|(id, data)| Some((id + 1, TileKind::from_data(data)?)))
+                .collect(),
+            tile_animations: tiles
+                .iter()
+                .filter_map(|(id, data)| Some((id + 1, TileAnimation::from_data(data)?)))
+                .collect(),
+        })
+    }
+    /// Bakes chunks that are in view and have changed. This switches cameras,
+    /// so it has to be done before setting the camera the world is drawn with.
+    pub fn bake_chunks(&mut self, assets: &Assets, view: Rect) {
+        for layer in self.layers.iter_mut() {
+            let offset = layer.draw_offset(view, self.parallax_origin);
+            let MapLayerKind::Tiles(tiles) = &mut layer.kind else {
+                continue;
+            };
+            for chunk in tiles.chunks.values_mut() {
+                if chunk.dirty && chunk.rect(offset).overlaps(&view) {
+                    chunk.bake(assets, &self.tile_animations);
                 }
             }
         }
-        None
     }
-    pub fn set_collision_tile(&mut self, x: i16, y: i16, tile: i16) {
-        let cx = ((x as f32 / 16.0).floor() * 16.0) as i16;
-        let cy = ((y as f32 / 16.0).floor() * 16.0) as i16;
+    /// Draws the layers that overlap `view`, either the ones behind the player
+    /// or the ones marked as foreground
+    pub fn draw(&self, assets: &Assets, view: Rect, foreground: bool) {
+        // all animated tiles share one clock so they stay in sync
+        let time = (get_time() * 1000.0) as u32;
+        for layer in self.layers.iter() {
+            if !layer.visible || layer.foreground != foreground {
+                continue;
+            }
+            let offset = layer.draw_offset(view, self.parallax_origin);
+            match &layer.kind {
+                MapLayerKind::Tiles(tiles) => {
+                    tiles.draw(
+                        assets,
+                        &self.tile_animations,
+                        time,
+                        view,
+                        offset,
+                        layer.color,
+                    );
+                }
+                MapLayerKind::Image {
+                    sprite,
+                    repeat_x,
+                    repeat_y,
+                } => draw_image_layer(sprite, offset, layer.color, *repeat_x, *repeat_y, view),
+            }
+        }
+    }
+    /// Finds the portal at a position. Portals are objects of type `portal`, with a `spawn`
+    /// property naming the spawn point to go to, and an optional `map` property if that
+    /// spawn point is in another map.
+    pub fn portal_at(&self, pos: Vec2) -> Option<Portal> {
+        let object = self.objects.iter().find(|f| {
+            f.kind == "portal"
+                && match f.shape {
+                    Shape::Point => pos.distance(Vec2::new(f.x, f.y)) <= 8.0,
+                    _ => f.contains(pos.x, pos.y),
+                }
+        })?;
+        Some(Portal {
+            map: object
+                .properties
+                .get("map")
+                .unwrap_or_else(|| self.name.clone()),
+            spawn: object.properties.get("spawn"),
+        })
+    }
+    /// Position of a spawn point, which is an object of type `spawn`
+    pub fn spawn_point(&self, name: &str) -> Option<Vec2> {
+        self.objects
+            .iter()
+            .find(|f| f.kind == "spawn" && f.name == name)
+            .map(|f| Vec2::new(f.x, f.y))
+    }
+    /// Removes the collision tiles under door objects with this name. Doors are
+    /// rectangle objects of type `door`.
+    pub fn open_door(&mut self, name: &str) {
+        let doors: Vec<Rect> = self
+            .objects
+            .iter()
+            .filter(|f| f.kind == "door" && f.name == name)
+            .filter_map(|f| match f.shape {
+                Shape::Rectangle { width, height } => Some(Rect::new(f.x, f.y, width, height)),
+                _ => None,
+            })
+            .collect();
+        for door in doors {
+            for y in (door.y / 8.0).floor() as i16..(door.bottom() / 8.0).ceil() as i16 {
+                for x in (door.x / 8.0).floor() as i16..(door.right() / 8.0).ceil() as i16 {
+                    self.set_tile(WorldLayer::Collision, x, y, Gid::EMPTY);
+                }
+            }
+        }
+    }
+    /// What kind of tile is at a tile position. Tiles with a type set in the tileset
+    /// behave the same on every layer, other tiles behave like the layer they're on.
+    pub fn tile_kind(&self, x: i16, y: i16) -> TileKind {
+        let kind_of = |tile: Gid, default: TileKind| {
+            if tile.is_empty() {
+                return None;
+            }
+            match self.tile_kinds.get(&tile.id()) {
+                Some(kind) => Some(*kind),
+                None if default != TileKind::None => Some(default),
+                None => None,
+            }
+        };
+        let roles = [
+            (WorldLayer::Collision, TileKind::Solid),
+            (WorldLayer::OneWayCollision, TileKind::OneWay),
+        ];
+        for (layer, default) in roles {
+            if let Some(kind) = kind_of(self.tile_at(layer, x, y), default) {
+                return kind;
+            }
+        }
+        // then every other layer, from front to back
+        for (index, layer) in self.layers.iter().enumerate().rev() {
+            if index == self.collision || index == self.one_way_collision {
+                continue;
+            }
+            let MapLayerKind::Tiles(tiles) = &layer.kind else {
+                continue;
+            };
+            if let Some(kind) = kind_of(tiles.tile_at(x, y), TileKind::None) {
+                return kind;
+            }
+        }
+        TileKind::None
+    }
+    pub fn layer(&self, layer: WorldLayer) -> &TileLayer {
+        let index = self.role_index(layer);
+        match &self.layers[index].kind {
+            MapLayerKind::Tiles(tiles) => tiles,
+            MapLayerKind::Image { .. } => unreachable!(),
+        }
+    }
+    pub fn layer_mut(&mut self, layer: WorldLayer) -> &mut TileLayer {
+        let index = self.role_index(layer);
+        match &mut self.layers[index].kind {
+            MapLayerKind::Tiles(tiles) => tiles,
+            MapLayerKind::Image { .. } => unreachable!(),
+        }
+    }
+    fn role_index(&self, layer: WorldLayer) -> usize {
+        match layer {
+            WorldLayer::Collision => self.collision,
+            WorldLayer::OneWayCollision => self.one_way_collision,
+        }
+    }
+    pub fn tile_at(&self, layer: WorldLayer, x: i16, y: i16) -> Gid {
+        self.layer(layer).tile_at(x, y)
+    }
+    pub fn set_tile(&mut self, layer: WorldLayer, x: i16, y: i16, tile: Gid) {
+        self.layer_mut(layer).set_tile(x, y, tile);
+    }
+}
 
-        let chunk = self
-            .collision
-            .iter_mut()
-            .find(|f| f.x == cx && f.y == cy)
-            .unwrap();
-        chunk.tiles[(x - chunk.x + (y - chunk.y) * 16) as usize] = tile;
+/// Where a portal object leads
+pub struct Portal {
+    pub map: String,
+    /// Name of the spawn point in `map`, from the `spawn` property
+    pub spawn: Option<String>,
+}
+
+/// Fades the screen out, switches maps, then fades back in
+pub struct Transition {
+    /// Map to switch to and its entities, until it's switched to. `None` if the portal
+    /// leads somewhere in the same map, which is kept as it is.
+    pub world: Option<(World, Vec<Entity>)>,
+    /// Where the player arrives in it
+    pub spawn: Vec2,
+    pub frame: u32,
+}
+impl Transition {
+    /// Length of the whole transition, in update frames
+    pub const LENGTH: u32 = 40;
+    /// Frame at which the map is switched, while the screen is fully black
+    pub const SWITCH_FRAME: u32 = Self::LENGTH / 2;
+
+    /// Loads where a portal in `current` leads before anything fades, so a broken portal
+    /// can be reported without moving the player
+    pub fn new(portal: &Portal, current: &World, locale: &Locale) -> Result<Self, String> {
+        let name = portal
+            .spawn
+            .as_deref()
+            .ok_or("portal has no `spawn` property")?;
+        let no_spawn = || format!("map {:?} has no spawn {name:?}", portal.map);
+        if portal.map == current.name {
+            return Ok(Self {
+                world: None,
+                spawn: current.spawn_point(name).ok_or_else(no_spawn)?,
+                frame: 0,
+            });
+        }
+        let world = World::load(&portal.map)
+            .map_err(|err| format!("failed to load map {:?}: {err}", portal.map))?;
+        let spawn = world.spawn_point(name).ok_or_else(no_spawn)?;
+        let entities = get_entities(&world, locale)
+            .map_err(|err| format!("failed to load npcs in map {:?}: {err}", portal.map))?;
+        Ok(Self {
+            world: Some((world, entities)),
+            spawn,
+            frame: 0,
+        })
+    }
+    pub fn is_finished(&self) -> bool {
+        self.frame >= Self::LENGTH
+    }
+    pub fn draw(&self, camera_pos: Vec2) {
+        let half = Self::SWITCH_FRAME as f32;
+        let alpha = 1.0 - (self.frame as f32 - half).abs() / half;
+        draw_rectangle(
+            camera_pos.x - SCREEN_WIDTH / 2.0,
+            camera_pos.y - SCREEN_HEIGHT / 2.0,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            Color::new(0.0, 0.0, 0.0, alpha),
+        );
     }
 }
-impl Default for World {
-    fn default() -> Self {
-        let xml = include_str!("../assets/tilemap/world.tmx");
-        let collision = get_layer(xml, "Collision");
-        let one_way_collision = get_layer(xml, "OneWayCollision");
-        let detail = get_layer(xml, "Detail");
-        let interactable = get_layer(xml, "Interactable");
-        let background = get_layer(xml, "Background");
-        World {
-            collision: get_all_chunks(collision),
-            one_way_collision: get_all_chunks(one_way_collision),
-            details: get_all_chunks(detail),
-            background: get_all_chunks(background),
-            interactable: get_all_chunks(interactable),
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TileKind {
+    None,
+    Solid,
+    /// Can be jumped through from below
+    OneWay,
+    /// Sends the player back to where they last stood
+    Hazard,
+    Ladder,
+    Water,
+    /// Heights (in pixels, from the bottom of the tile) at the left and right edges
+    Slope {
+        left: f32,
+        right: f32,
+    },
+}
+impl TileKind {
+    fn from_data(data: &TileData) -> Option<Self> {
+        Some(match data.kind.as_str() {
+            "solid" => TileKind::Solid,
+            "one_way" => TileKind::OneWay,
+            "hazard" => TileKind::Hazard,
+            "ladder" => TileKind::Ladder,
+            "water" => TileKind::Water,
+            "slope" => TileKind::Slope {
+                left: data.properties.get("left").unwrap_or(0.0),
+                right: data.properties.get("right").unwrap_or(8.0),
+            },
+            // tiles with a collision shape but no type are just solid
+            "" if !data.collision.is_empty() => TileKind::Solid,
+            _ => return None,
+        })
+    }
+}
+
+pub struct TileAnimation {
+    /// Tile ids and how long to show them for, in milliseconds
+    frames: Vec<(u32, u32)>,
+    total_length: u32,
+}
+impl TileAnimation {
+    fn from_data(data: &TileData) -> Option<Self> {
+        let frames: Vec<(u32, u32)> = data
+            .animation
+            .iter()
+            .map(|f| (f.tile_id + 1, f.duration))
+            .collect();
+        let total_length = frames.iter().map(|f| f.1).sum();
+        if total_length == 0 {
+            return None;
         }
+        Some(Self {
+            frames,
+            total_length,
+        })
+    }
+    pub fn get_at_time(&self, mut time: u32) -> u32 {
+        time %= self.total_length;
+        for (tile, length) in self.frames.iter() {
+            if time >= *length {
+                time -= length;
+            } else {
+                return *tile;
+            }
+        }
+        panic!()
     }
 }
 
+pub struct MapLayer {
+    pub visible: bool,
+    /// Tint, with the layer's opacity as alpha
+    pub color: Color,
+    pub offset: Vec2,
+    /// How fast the layer scrolls compared to the camera
+    pub parallax: Vec2,
+    /// Drawn in front of the player, set with the `foreground` layer property
+    pub foreground: bool,
+    pub kind: MapLayerKind,
+}
+impl MapLayer {
+    /// Where the layer is drawn when looking at `view`, after scrolling it for parallax
+    fn draw_offset(&self, view: Rect, parallax_origin: Vec2) -> Vec2 {
+        (self.offset + (view.center() - parallax_origin) * (Vec2::ONE - self.parallax)).floor()
+    }
+}
+
+pub enum MapLayerKind {
+    Tiles(TileLayer),
+    Image {
+        sprite: Sprite,
+        repeat_x: bool,
+        repeat_y: bool,
+    },
+}
+
+/// Draws an image layer, tiling it across the view if it repeats
+fn draw_image_layer(
+    sprite: &Sprite,
+    offset: Vec2,
+    color: Color,
+    repeat_x: bool,
+    repeat_y: bool,
+    view: Rect,
+) {
+    let size = sprite.size();
+    // when repeating, start at the first copy that's in view
+    let first = |offset: f32, view_start: f32, size: f32, repeat: bool| {
+        if repeat {
+            offset + ((view_start - offset) / size).floor() * size
+        } else {
+            offset
+        }
+    };
+    let start_x = first(offset.x, view.x, size.x, repeat_x);
+    let mut y = first(offset.y, view.y, size.y, repeat_y);
+    loop {
+        let mut x = start_x;
+        loop {
+            if Rect::new(x, y, size.x, size.y).overlaps(&view) {
+                sprite.draw(x, y, color);
+            }
+            x += size.x;
+            if !repeat_x || x >= view.right() {
+                break;
+            }
+        }
+        y += size.y;
+        if !repeat_y || y >= view.bottom() {
+            break;
+        }
+    }
+}
+
+pub struct TileLayer {
+    /// Chunks by the tile position of their top left corner
+    pub chunks: HashMap<(i16, i16), Chunk>,
+}
+impl TileLayer {
+    fn from_map(map: &Map, name: &str, tile_chunks: &[TileChunk]) -> Result<Self, MapError> {
+        // gids are offset by the tileset's firstgid, we want 1 to be the first tile.
+        // only the first tileset is drawn, so tiles from any others are invalid too
+        let (gid_offset, tile_count) = map
+            .tilesets
+            .first()
+            .map(|f| (f.first_gid.saturating_sub(1), f.tileset.tile_count))
+            .unwrap_or((0, 0));
+
+        // tiled chunks can be any size (and finite maps are one big chunk),
+        // so split everything into our own 16x16 chunks
+        let mut tile_layer = TileLayer {
+            chunks: HashMap::new(),
+        };
+        for tile_chunk in tile_chunks {
+            for local_y in 0..tile_chunk.height {
+                for local_x in 0..tile_chunk.width {
+                    let tile = tile_chunk.tiles[(local_x + local_y * tile_chunk.width) as usize];
+                    if tile.is_empty() {
+                        continue;
+                    }
+                    let id = tile
+                        .id()
+                        .checked_sub(gid_offset)
+                        .filter(|f| (1..=tile_count).contains(f))
+                        .ok_or_else(|| MapError::InvalidTile {
+                            layer: name.to_string(),
+                            gid: tile.id(),
+                        })?;
+                    tile_layer.set_tile(
+                        (tile_chunk.x + local_x as i32) as i16,
+                        (tile_chunk.y + local_y as i32) as i16,
+                        tile.with_id(id),
+                    );
+                }
+            }
+        }
+        Ok(tile_layer)
+    }
+    pub fn tile_at(&self, x: i16, y: i16) -> Gid {
+        let Some(chunk) = self.chunks.get(&chunk_pos(x, y)) else {
+            return Gid::EMPTY;
+        };
+        chunk
+            .tile_at((x - chunk.x) as _, (y - chunk.y) as _)
+            .unwrap_or(Gid::EMPTY)
+    }
+    /// Sets a tile, creating its chunk if needed
+    pub fn set_tile(&mut self, x: i16, y: i16, tile: Gid) {
+        let (cx, cy) = chunk_pos(x, y);
+        if tile.is_empty() && !self.chunks.contains_key(&(cx, cy)) {
+            return;
+        }
+        let chunk = self.chunks.entry((cx, cy)).or_insert_with(|| Chunk {
+            x: cx,
+            y: cy,
+            tiles: vec![Gid::EMPTY; 16 * 16],
+            baked: None,
+            animated_tiles: Vec::new(),
+            dirty: true,
+        });
+        let stored = &mut chunk.tiles[(x - cx + (y - cy) * 16) as usize];
+        // so setting the same tile every frame doesn't bake the chunk every frame
+        if *stored == tile {
+            return;
+        }
+        *stored = tile;
+        chunk.dirty = true;
+    }
+    pub fn draw(
+        &self,
+        assets: &Assets,
+        animations: &HashMap<u32, TileAnimation>,
+        time: u32,
+        view: Rect,
+        offset: Vec2,
+        color: Color,
+    ) {
+        for chunk in self.chunks.values() {
+            if chunk.rect(offset).overlaps(&view) {
+                chunk.draw(assets, offset, color, animations, time);
+            }
+        }
+    }
+}
+
+/// Tile position of the top left corner of the chunk containing a tile
+fn chunk_pos(x: i16, y: i16) -> (i16, i16) {
+    (x.div_euclid(16) * 16, y.div_euclid(16) * 16)
+}
+
 pub struct Chunk {
     pub x: i16,
     pub y: i16,
-    pub tiles: Vec<i16>,
+    tiles: Vec<Gid>,
+    /// All static tiles drawn to a texture, so the chunk can be drawn in one go
+    baked: Option<RenderTarget>,
+    /// Indices of animated tiles, which are drawn on top of the baked texture every frame
+    animated_tiles: Vec<usize>,
+    /// Whether tiles have changed since the chunk was last baked
+    dirty: bool,
 }
 impl Chunk {
-    pub fn tile_at(&self, x: usize, y: usize) -> Option<i16> {
+    pub fn tile_at(&self, x: usize, y: usize) -> Option<Gid> {
         if x > 16 {
             return None;
         }
         self.tiles.get(x + y * 16).cloned()
     }
-    pub fn draw(&self, assets: &Assets) {
-        for (index, tile) in self.tiles.iter().enumerate() {
-            if *tile == 0 {
+    /// The area the chunk covers, in pixels
+    pub fn rect(&self, offset: Vec2) -> Rect {
+        Rect::new(
+            self.x as f32 * 8.0 + offset.x,
+            self.y as f32 * 8.0 + offset.y,
+            16.0 * 8.0,
+            16.0 * 8.0,
+        )
+    }
+    fn bake(&mut self, assets: &Assets, animations: &HashMap<u32, TileAnimation>) {
+        let rect = self.rect(Vec2::ZERO);
+        let target = self.baked.get_or_insert_with(|| {
+            let rt = render_target(rect.w as u32, rect.h as u32);
+            rt.texture.set_filter(FilterMode::Nearest);
+            rt
+        });
+        set_camera(&Camera2D {
+            render_target: Some(target.clone()),
+            zoom: Vec2::new(2.0 / rect.w, 2.0 / rect.h),
+            target: rect.center(),
+            ..Default::default()
+        });
+        clear_background(Color::new(0.0, 0.0, 0.0, 0.0));
+
+        let mut animated_tiles = Vec::new();
+        for (index, gid) in self.tiles.iter().enumerate() {
+            if gid.is_empty() {
                 continue;
             }
-            let tile = *tile - 1;
-            let x = index % 16;
-            let y = index / 16;
-            assets.tileset.draw_tile(
-                self.x as f32 * 8.0 + (x * 8) as f32,
-                self.y as f32 * 8.0 + (y * 8) as f32,
-                (tile % 32) as f32,
-                (tile / 32) as f32,
-                None,
-            );
+            if animations.contains_key(&gid.id()) {
+                animated_tiles.push(index);
+                continue;
+            }
+            self.draw_tile(assets, index, gid.id(), Vec2::ZERO, WHITE);
         }
+        self.animated_tiles = animated_tiles;
+        self.dirty = false;
+    }
+    pub fn draw(
+        &self,
+        assets: &Assets,
+        offset: Vec2,
+        color: Color,
+        animations: &HashMap<u32, TileAnimation>,
+        time: u32,
+    ) {
+        let tile_id = |gid: Gid| match animations.get(&gid.id()) {
+            Some(animation) => animation.get_at_time(time),
+            None => gid.id(),
+        };
+        match &self.baked {
+            Some(baked) if !self.dirty => {
+                let rect = self.rect(offset);
+                draw_texture(&baked.texture, rect.x, rect.y, color);
+                for index in self.animated_tiles.iter() {
+                    let gid = self.tiles[*index];
+                    self.draw_tile(assets, *index, tile_id(gid), offset, color);
+                }
+            }
+            // not baked yet, so draw every tile by itself
+            _ => {
+                for (index, gid) in self.tiles.iter().enumerate() {
+                    if !gid.is_empty() {
+                        self.draw_tile(assets, index, tile_id(*gid), offset, color);
+                    }
+                }
+            }
+        }
+    }
+    /// Draws the tile at `index` as `tile_id`, keeping its flip flags
+    fn draw_tile(&self, assets: &Assets, index: usize, tile_id: u32, offset: Vec2, color: Color) {
+        let tile = tile_id - 1;
+        let x = index % 16;
+        let y = index / 16;
+        assets.tileset.draw_tile_ex(
+            self.x as f32 * 8.0 + (x * 8) as f32 + offset.x,
+            self.y as f32 * 8.0 + (y * 8) as f32 + offset.y,
+            (tile % 32) as f32,
+            (tile / 32) as f32,
+            color,
+            gid_draw_params(self.tiles[index]).as_ref(),
+        );
     }
 }
 
-fn get_all_chunks(xml: &str) -> Vec<Chunk> {
-    let mut chunks = Vec::new();
-    let mut xml = xml.to_string();
-    while let Some((current, remains)) = xml.split_once("</chunk>") {
-        let new = parse_chunk(current);
-        chunks.push(new);
-        xml = remains.to_string();
-    }
-
-    chunks
-}
-
-fn get_layer<'a>(xml: &'a str, layer: &str) -> &'a str {
-    let split = format!(" name=\"{layer}");
-    xml.split_once(&split)
-        .unwrap()
-        .1
-        .split_once(">")
-        .unwrap()
-        .1
-        .split_once("</layer>")
-        .unwrap()
-        .0
-}
-
-fn parse_chunk(xml: &str) -> Chunk {
-    let (tag, data) = xml
-        .split_once("<chunk ")
-        .unwrap()
-        .1
-        .split_once(">")
-        .unwrap();
-
-    let x = tag
-        .split_once("x=\"")
-        .unwrap()
-        .1
-        .split_once("\"")
-        .unwrap()
-        .0
-        .parse()
-        .unwrap();
-    let y = tag
-        .split_once("y=\"")
-        .unwrap()
-        .1
-        .split_once("\"")
-        .unwrap()
-        .0
-        .parse()
-        .unwrap();
-
-    let mut split = data.split(',');
-
-    let mut chunk = vec![0; 16 * 16];
-    for item in &mut chunk {
-        let a = split.next().unwrap().trim();
-        *item = a.parse().unwrap()
-    }
-    Chunk { x, y, tiles: chunk }
+/// Converts tiled's flip flags to draw params, or `None` if the tile isn't flipped
+fn gid_draw_params(gid: Gid) -> Option<DrawTextureParams> {
+    if !gid.flipped_horizontally() && !gid.flipped_vertically() && !gid.flipped_diagonally() {
+        return None;
+    }
+    // tiled applies the diagonal flip (swapping x and y) first, then the others.
+    // macroquad flips the source first and then rotates, so a diagonal flip
+    // becomes a quarter turn with the remaining flips shuffled around
+    Some(if gid.flipped_diagonally() {
+        DrawTextureParams {
+            rotation: std::f32::consts::FRAC_PI_2,
+            flip_x: gid.flipped_vertically(),
+            flip_y: !gid.flipped_horizontally(),
+            ..Default::default()
+        }
+    } else {
+        DrawTextureParams {
+            flip_x: gid.flipped_horizontally(),
+            flip_y: gid.flipped_vertically(),
+            ..Default::default()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A font where every glyph is 3 pixels wide, except spaces which are 2
+    fn font() -> Font {
+        let glyph = |width| Glyph {
+            source: Vec2::ZERO,
+            width,
+        };
+        let mut glyphs: HashMap<char, Glyph> = ('a'..='z').map(|f| (f, glyph(3.0))).collect();
+        glyphs.insert(' ', glyph(2.0));
+        glyphs.insert(Font::FALLBACK, glyph(3.0));
+        Font {
+            sprite: atlas::fake(64.0, 64.0),
+            cell: Vec2::new(4.0, 6.0),
+            glyphs,
+            kerning: HashMap::new(),
+        }
+    }
+    fn wrap(text: &str, max_width: f32) -> Vec<(&str, f32)> {
+        font()
+            .wrap(text, max_width)
+            .into_iter()
+            .map(|f| (&text[f.range], f.width))
+            .collect()
+    }
+
+    #[test]
+    fn wrap_fits_on_one_line() {
+        assert_eq!(wrap("ab cd", 100.0), [("ab cd", 18.0)]);
+        assert_eq!(wrap("", 100.0), [("", 0.0)]);
+    }
+
+    #[test]
+    fn wrap_breaks_at_spaces() {
+        assert_eq!(wrap("ab cd", 10.0), [("ab", 7.0), ("cd", 7.0)]);
+        // exactly as wide as the line still fits
+        assert_eq!(wrap("ab cd", 18.0), [("ab cd", 18.0)]);
+    }
+
+    #[test]
+    fn wrap_breaks_long_words() {
+        assert_eq!(
+            wrap("abcdefgh", 11.0),
+            [("abc", 11.0), ("def", 11.0), ("gh", 7.0)]
+        );
+        assert_eq!(
+            wrap("ab cdefgh", 11.0),
+            [("ab", 7.0), ("cde", 11.0), ("fgh", 11.0)]
+        );
+        // a line too narrow for any character still gets one per line
+        assert_eq!(wrap("abc", 0.0), [("a", 3.0), ("b", 3.0), ("c", 3.0)]);
+        // and never splits a character in two
+        assert_eq!(
+            wrap("\u{e5}\u{e5}\u{e5}", 7.0),
+            [("\u{e5}\u{e5}", 7.0), ("\u{e5}", 3.0)]
+        );
+    }
+
+    #[test]
+    fn wrap_keeps_empty_paragraphs() {
+        assert_eq!(
+            wrap("ab\n\ncd", 100.0),
+            [("ab", 7.0), ("", 0.0), ("cd", 7.0)]
+        );
+        assert_eq!(wrap("ab\n", 100.0), [("ab", 7.0), ("", 0.0)]);
+    }
+
+    #[test]
+    fn wrap_handles_double_spaces() {
+        // spaces count towards where the next word goes, but not towards a line's width
+        assert_eq!(wrap("ab  cd", 100.0), [("ab  cd", 21.0)]);
+        let lines = wrap("ab  cd", 10.0);
+        assert_eq!(lines.iter().map(|f| f.1).collect::<Vec<_>>(), [7.0, 7.0]);
+        assert!(lines.iter().all(|f| !f.0.starts_with(' ')));
+    }
+
+    /// A 2x1 map with a tileset of 4 tiles at `first_gid`, then another of 4 tiles
+    fn tile_layer(first_gid: u32, tiles: &str) -> Result<TileLayer, MapError> {
+        let xml = format!(
+            r#"<map width="2" height="1" tilewidth="8" tileheight="8">
+ <tileset firstgid="{first_gid}" tilecount="4"/>
+ <tileset firstgid="{}" tilecount="4"/>
+ <layer id="1" name="tiles"><data encoding="csv">{tiles}</data></layer>
+</map>"#,
+            first_gid + 4
+        );
+        let map = Map::parse(&xml, |_| None)?;
+        let LayerKind::Tiles(chunks) = &map.layers[0].kind else {
+            unreachable!()
+        };
+        TileLayer::from_map(&map, "tiles", chunks)
+    }
+
+    #[test]
+    fn tile_layer_only_has_tiles_from_the_tileset() {
+        let layer = tile_layer(1, "1,4").unwrap();
+        assert_eq!(layer.tile_at(0, 0).id(), 1);
+        assert_eq!(layer.tile_at(1, 0).id(), 4);
+        // ids start at 1 whatever the tileset's firstgid is
+        let layer = tile_layer(3, "3,0").unwrap();
+        assert_eq!(layer.tile_at(0, 0).id(), 1);
+        assert!(layer.tile_at(1, 0).is_empty());
+
+        for (first_gid, tiles, gid) in [(3, "2,3", 2), (1, "1,5", 5), (1, "9,1", 9)] {
+            assert!(matches!(
+                tile_layer(first_gid, tiles),
+                Err(MapError::InvalidTile { gid: found, .. }) if found == gid
+            ));
+        }
+    }
 }
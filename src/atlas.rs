@@ -0,0 +1,172 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use image::RgbaImage;
+use macroquad::prelude::*;
+
+/// Width and height of an atlas page. Images bigger than this get a page to themselves.
+const PAGE_SIZE: u32 = 1024;
+/// Empty space between images, so they don't bleed into each other
+const PADDING: u32 = 1;
+
+/// An image packed into an atlas page. Sprites on the same page can be drawn in one batch.
+#[derive(Clone)]
+pub struct Sprite {
+    texture: Texture2D,
+    /// Where the image is on the page
+    source: Rect,
+}
+impl Sprite {
+    pub fn width(&self) -> f32 {
+        self.source.w
+    }
+    pub fn height(&self) -> f32 {
+        self.source.h
+    }
+    pub fn size(&self) -> Vec2 {
+        self.source.size()
+    }
+    pub fn draw(&self, x: f32, y: f32, color: Color) {
+        self.draw_ex(x, y, color, DrawTextureParams::default());
+    }
+    /// Same as `draw_texture_ex`. `params.source` is relative to the sprite.
+    pub fn draw_ex(&self, x: f32, y: f32, color: Color, mut params: DrawTextureParams) {
+        let source = params
+            .source
+            .unwrap_or(Rect::new(0.0, 0.0, self.source.w, self.source.h));
+        params.source = Some(source.offset(self.source.point()));
+        params.dest_size = params.dest_size.or(Some(source.size()));
+        draw_texture_ex(&self.texture, x, y, color, params);
+    }
+}
+
+/// A sprite that can't be drawn, for tests that run without a window
+#[cfg(test)]
+pub fn fake(width: f32, height: f32) -> Sprite {
+    use macroquad::miniquad::{RawId, TextureId};
+    Sprite {
+        texture: Texture2D::from_miniquad_texture(TextureId::from_raw_id(RawId::OpenGl(0))),
+        source: Rect::new(0.0, 0.0, width, height),
+    }
+}
+
+/// Packs an image into the atlas. Images that are already in the atlas aren't packed again.
+pub fn add(image: &RgbaImage) -> Sprite {
+    ATLAS.with_borrow_mut(|f| f.add(image))
+}
+
+/// Starts packing into new pages, so the old ones are freed once no sprite uses them.
+/// Used before reloading everything, so pages from old reloads don't pile up.
+pub fn clear() {
+    ATLAS.take();
+}
+
+thread_local! {
+    static ATLAS: RefCell<Atlas> = RefCell::default();
+}
+
+#[derive(Default)]
+struct Atlas {
+    pages: Vec<Page>,
+    /// Every packed image, by a hash of its pixels
+    sprites: HashMap<u64, Sprite>,
+}
+impl Atlas {
+    fn add(&mut self, image: &RgbaImage) -> Sprite {
+        let mut hasher = DefaultHasher::new();
+        image.dimensions().hash(&mut hasher);
+        image.as_raw().hash(&mut hasher);
+        let key = hasher.finish();
+        if let Some(sprite) = self.sprites.get(&key) {
+            return sprite.clone();
+        }
+
+        let (width, height) = image.dimensions();
+        let (page, x, y) = self.allocate(width, height);
+        let texture = &self.pages[page].texture;
+        texture.update_part(
+            &Image {
+                width: width as u16,
+                height: height as u16,
+                bytes: image.as_raw().clone(),
+            },
+            x as i32,
+            y as i32,
+            width as i32,
+            height as i32,
+        );
+        let sprite = Sprite {
+            texture: texture.clone(),
+            source: Rect::new(x as f32, y as f32, width as f32, height as f32),
+        };
+        self.sprites.insert(key, sprite.clone());
+        sprite
+    }
+    /// Finds room for an image, adding a page if none of them have space
+    fn allocate(&mut self, width: u32, height: u32) -> (usize, u32, u32) {
+        for (index, page) in self.pages.iter_mut().enumerate() {
+            if let Some((x, y)) = page.allocate(width, height) {
+                return (index, x, y);
+            }
+        }
+        let mut page = Page::new(PAGE_SIZE.max(width).max(height));
+        let (x, y) = page.allocate(width, height).unwrap();
+        self.pages.push(page);
+        (self.pages.len() - 1, x, y)
+    }
+}
+
+/// A texture filled with rows of images, called shelves
+struct Page {
+    texture: Texture2D,
+    size: u32,
+    shelves: Vec<Shelf>,
+}
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// Where the next image goes
+    x: u32,
+}
+impl Page {
+    fn new(size: u32) -> Self {
+        let texture = Texture2D::from_rgba8(
+            size as u16,
+            size as u16,
+            &vec![0; (size * size * 4) as usize],
+        );
+        texture.set_filter(FilterMode::Nearest);
+        Self {
+            texture,
+            size,
+            shelves: Vec::new(),
+        }
+    }
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        // put the image on the shortest shelf it fits on, to waste as little space as possible
+        let size = self.size;
+        let shelf = self
+            .shelves
+            .iter_mut()
+            .filter(|f| f.height >= height && f.x + width <= size)
+            .min_by_key(|f| f.height);
+        if let Some(shelf) = shelf {
+            let x = shelf.x;
+            shelf.x += width + PADDING;
+            return Some((x, shelf.y));
+        }
+        let y = self.shelves.last().map_or(0, |f| f.y + f.height + PADDING);
+        if width > size || y + height > size {
+            return None;
+        }
+        self.shelves.push(Shelf {
+            y,
+            height,
+            x: width + PADDING,
+        });
+        Some((0, y))
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+
+use macroquad::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    assets::{AssetError, Assets, read_asset},
+    entities::Entity,
+    input::{Action, Input},
+    npcs::{Condition, Effect},
+    player::Player,
+    text::{RichText, Typewriter, markup},
+    utils::*,
+};
+
+/// Every conversation in `dialogue.ron`, by name. Each is made of parts, by name,
+/// and starts at the part called `start`.
+#[derive(Default)]
+pub struct Conversations(HashMap<String, HashMap<String, Node>>);
+impl Conversations {
+    /// Reads `dialogue.ron`. If it can't be read that's reported, and there are no conversations.
+    pub fn load() -> Self {
+        let path = "dialogue.ron";
+        let conversations = read_asset(path).and_then(|bytes| {
+            ron::de::from_bytes(&bytes).map_err(|err| AssetError::new(path, err))
+        });
+        match conversations {
+            Ok(conversations) => Self(conversations),
+            Err(err) => {
+                eprintln!("failed to load {err}");
+                Self::default()
+            }
+        }
+    }
+    fn get(&self, name: &str) -> Option<&HashMap<String, Node>> {
+        let conversation = self.0.get(name);
+        if conversation.is_none() {
+            eprintln!("no conversation {name:?} in dialogue.ron");
+        }
+        conversation
+    }
+}
+
+/// Part of a conversation: some lines, then a choice of where to go next
+#[derive(Clone, Default, Deserialize)]
+struct Node {
+    /// Said one after another
+    lines: Vec<Line>,
+    /// Offered after the last line. Choices whose conditions don't hold aren't shown,
+    /// and if none are, the conversation goes on to `next`.
+    #[serde(default)]
+    choices: Vec<Choice>,
+    /// Part to go to after the last line, or the end of the conversation if `None`
+    #[serde(default)]
+    next: Option<String>,
+    /// Done when the part is reached
+    #[serde(default)]
+    then: Vec<Effect>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Line {
+    /// Key of the speaker's name, or `None` for narration
+    #[serde(default)]
+    speaker: Option<String>,
+    /// Key of what they say
+    text: String,
+}
+
+#[derive(Clone, Deserialize)]
+struct Choice {
+    /// Key of what the player says
+    text: String,
+    /// Only offered while all of these hold
+    #[serde(default)]
+    when: Vec<Condition>,
+    /// Done when it's picked
+    #[serde(default)]
+    then: Vec<Effect>,
+    /// Part to go to, or the end of the conversation if `None`
+    #[serde(default)]
+    next: Option<String>,
+}
+
+/// A conversation the player is having with an entity, shown in a box at the bottom
+/// of the screen. Conditions and effects are run by the entity, like its own.
+pub struct Dialogue {
+    nodes: HashMap<String, Node>,
+    /// Index of the entity being talked to
+    pub entity: usize,
+    node: Node,
+    line: usize,
+    speaker: Option<RichText>,
+    /// Voice of the speaker of the current line
+    voice: Option<Voice>,
+    text: RichText,
+    typewriter: Typewriter,
+    /// Choices that can be picked in this part, with their text
+    choices: Vec<(Choice, RichText)>,
+    selected: usize,
+    finished: bool,
+    /// Conversations started since the player last went on, to stop ones that
+    /// start each other from going round forever
+    started: Vec<String>,
+    /// Where the mouse was last frame, so a mouse resting on a choice doesn't take
+    /// over from the keys
+    last_mouse: Option<Vec2>,
+}
+impl Dialogue {
+    const MARGIN: f32 = 4.0;
+    const PADDING: f32 = 4.0;
+    /// Space left of each choice for the marker
+    const INDENT: f32 = 6.0;
+
+    /// Starts the conversation called `name` with `entity`, the entity at index `index`
+    pub fn start(
+        name: &str,
+        index: usize,
+        entity: &mut Entity,
+        player: &mut Player,
+        assets: &Assets,
+        input: &Input,
+    ) -> Option<Self> {
+        let nodes = assets.conversations.get(name)?.clone();
+        let mut dialogue = Self {
+            nodes,
+            entity: index,
+            node: Node::default(),
+            line: 0,
+            speaker: None,
+            voice: None,
+            text: markup(""),
+            typewriter: Typewriter::default(),
+            choices: Vec::new(),
+            selected: 0,
+            finished: false,
+            started: vec![name.to_string()],
+            last_mouse: None,
+        };
+        dialogue.go_to(Some("start"), entity, player, assets, input);
+        Some(dialogue)
+    }
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+    fn go_to(
+        &mut self,
+        name: Option<&str>,
+        entity: &mut Entity,
+        player: &mut Player,
+        assets: &Assets,
+        input: &Input,
+    ) {
+        let Some(node) = name.and_then(|f| self.nodes.get(f)) else {
+            if let Some(name) = name {
+                eprintln!("conversation has no part {name:?}");
+            }
+            self.finished = true;
+            return;
+        };
+        self.node = node.clone();
+        let then = self.node.then.clone();
+        if self.apply(&then, entity, player, assets, input) {
+            return;
+        }
+        self.choices = self
+            .node
+            .choices
+            .iter()
+            .filter(|f| f.when.iter().all(|f| entity.check(f, player)))
+            .map(|f| (f.clone(), markup(&assets.locale.get(&f.text, &[]))))
+            .collect();
+        self.selected = 0;
+        self.show_line(0, assets);
+    }
+    fn show_line(&mut self, index: usize, assets: &Assets) {
+        self.line = index;
+        let line = self.node.lines.get(index);
+        let speaker = line.and_then(|f| f.speaker.as_deref());
+        self.speaker = speaker.map(|f| markup(&assets.locale.get(f, &[])));
+        self.voice = speaker.and_then(voice);
+        self.text = markup(&line.map_or(String::new(), |f| assets.locale.get(&f.text, &[])));
+        self.typewriter.reset();
+    }
+    /// Does effects with the entity. Returns whether one of them started another
+    /// conversation, which this switches to, or ended this one.
+    fn apply(
+        &mut self,
+        effects: &[Effect],
+        entity: &mut Entity,
+        player: &mut Player,
+        assets: &Assets,
+        input: &Input,
+    ) -> bool {
+        let mut talk = None;
+        for effect in effects {
+            talk = talk.or(entity.apply(effect, player, assets, input));
+        }
+        let Some(name) = talk else {
+            return false;
+        };
+        if self.started.contains(&name) {
+            eprintln!("conversation {name:?} starts itself again before the player can answer");
+            self.finished = true;
+            return true;
+        }
+        let Some(nodes) = assets.conversations.get(&name) else {
+            return false;
+        };
+        self.nodes = nodes.clone();
+        self.started.push(name);
+        self.go_to(Some("start"), entity, player, assets, input);
+        true
+    }
+    /// Whether the choices are shown, which is once the last line is typed out
+    fn showing_choices(&self) -> bool {
+        !self.choices.is_empty()
+            && self.line + 1 >= self.node.lines.len()
+            && self.typewriter.is_finished(&self.text)
+    }
+    /// Where the box and each choice go on the screen
+    fn layout(&self, assets: &Assets) -> (Rect, Vec<Rect>) {
+        let width = SCREEN_WIDTH - Self::MARGIN * 2.0;
+        let inner_width = width - Self::PADDING * 2.0;
+        let mut height = assets.measure_text(&self.text.text, inner_width).y;
+        let mut choices = Vec::new();
+        if self.showing_choices() {
+            for (_, text) in self.choices.iter() {
+                height += Self::PADDING;
+                let size = assets.measure_text(&text.text, inner_width - Self::INDENT);
+                choices.push(Rect::new(0.0, height, inner_width, size.y));
+                height += size.y;
+            }
+        }
+        height += Self::PADDING * 2.0;
+        let bounds = Rect::new(
+            Self::MARGIN,
+            SCREEN_HEIGHT - Self::MARGIN - height,
+            width,
+            height,
+        );
+        let choices = choices
+            .into_iter()
+            .map(|f| f.offset(bounds.point() + Vec2::splat(Self::PADDING)))
+            .collect();
+        (bounds, choices)
+    }
+    /// Handles input. `mouse` is in screen pixels.
+    pub fn update(
+        &mut self,
+        input: &Input,
+        mouse: Vec2,
+        entity: &mut Entity,
+        player: &mut Player,
+        assets: &Assets,
+    ) {
+        self.typewriter.update(&self.text, 1000.0 / 60.0, |char| {
+            if let Some(voice) = self.voice {
+                voice(char);
+            }
+        });
+
+        let (_, choice_rects) = self.layout(assets);
+        let hovered = choice_rects.iter().position(|f| f.contains(mouse));
+        let clicked = is_mouse_button_pressed(MouseButton::Left);
+        let moved = self.last_mouse.is_some_and(|f| f != mouse);
+        self.last_mouse = Some(mouse);
+        if self.showing_choices() {
+            if let Some(hovered) = hovered
+                && (moved || clicked)
+            {
+                self.selected = hovered;
+            }
+            let count = self.choices.len();
+            if input.pressed(Action::Up) {
+                self.selected = (self.selected + count - 1) % count;
+            }
+            if input.pressed(Action::Down) {
+                self.selected = (self.selected + 1) % count;
+            }
+        }
+        // clicking anywhere goes on, except next to the choices
+        if !input.pressed(Action::Confirm)
+            && !(clicked && (hovered.is_some() || !self.showing_choices()))
+        {
+            return;
+        }
+        self.started.clear();
+
+        if !self.typewriter.is_finished(&self.text) {
+            self.typewriter.skip(&self.text);
+        } else if self.showing_choices() {
+            let choice = self.choices[self.selected].0.clone();
+            if self.apply(&choice.then, entity, player, assets, input) {
+                return;
+            }
+            self.go_to(choice.next.as_deref(), entity, player, assets, input);
+        } else if self.line + 1 < self.node.lines.len() {
+            self.show_line(self.line + 1, assets);
+        } else {
+            let next = self.node.next.clone();
+            self.go_to(next.as_deref(), entity, player, assets, input);
+        }
+    }
+    pub fn draw(&self, assets: &Assets, camera_pos: Vec2) {
+        let origin = camera_pos - Vec2::new(SCREEN_WIDTH, SCREEN_HEIGHT) / 2.0;
+        let (bounds, choice_rects) = self.layout(assets);
+        let bounds = bounds.offset(origin);
+        assets
+            .bubble_panel
+            .draw(bounds.x, bounds.y, bounds.w, bounds.h);
+
+        if let Some(speaker) = &self.speaker {
+            let padding = 2.0;
+            let size = assets.measure_text(&speaker.text, f32::INFINITY) + padding * 2.0;
+            let x = bounds.x + Self::PADDING;
+            let y = bounds.y - size.y + 1.0;
+            assets.tooltip_panel.draw(x, y, size.x, size.y);
+            assets.draw_text(speaker, x + padding, y + padding, f32::INFINITY);
+        }
+
+        let inner_width = bounds.w - Self::PADDING * 2.0;
+        assets.draw_text_revealed(
+            &self.text,
+            bounds.x + Self::PADDING,
+            bounds.y + Self::PADDING,
+            inner_width,
+            self.typewriter.revealed(),
+        );
+        for (index, ((_, text), rect)) in self.choices.iter().zip(choice_rects).enumerate() {
+            let rect = rect.offset(origin);
+            if index == self.selected {
+                assets.draw_text(&markup(">"), rect.x, rect.y, f32::INFINITY);
+            }
+            assets.draw_text(
+                text,
+                rect.x + Self::INDENT,
+                rect.y,
+                inner_width - Self::INDENT,
+            );
+        }
+    }
+}
@@ -1,432 +1,380 @@
 use crate::{
-    assets::{Animation, Assets, World},
+    assets::{Animation, AssetError, Assets, World, load_palette},
+    input::{Action, Input},
+    locale::Locale,
+    npcs::{Condition, Effect, Show, State, load_npcs},
     player::{Player, Tag},
+    text::{RichText, Typewriter, markup},
+    tiled::{Object, Shape},
     utils::*,
 };
 use macroquad::prelude::*;
+use serde::de::DeserializeOwned;
 
 pub enum DrawType {
     None,
     Animation(Animation),
-    TextBubble(String),
+    TextBubble(RichText),
+}
+
+/// Where the player has to be to interact with an entity
+#[derive(Clone)]
+pub enum Area {
+    /// Within this distance of the entity's position
+    Radius(f32),
+    /// Inside a shape drawn in the map
+    Object(Object),
 }
 
 pub struct Entity {
     pub pos: Vec2,
-    pub draw_condition: &'static dyn Fn(&mut Entity, &mut Player, &Assets) -> bool,
+    /// Drawn while all of these hold
+    pub conditions: Vec<Condition>,
+    /// Done every frame while drawn
+    pub effects: Vec<Effect>,
     pub draw_type: DrawType,
     pub anim_frame: u32,
+    /// Tag of the animation to play, or `None` to loop through the whole file
+    pub animation_tag: Option<String>,
+    pub area: Area,
+    /// Reveals the entity's text bubble
+    pub typewriter: Typewriter,
+    /// Called with each character of the text bubble as it's revealed, like to play a voice blip
+    pub voice: Option<Voice>,
+    /// Id of the npc's map object and index of its state the entity is for, so reloading
+    /// can keep the entity where it was. `None` for entities that aren't npcs.
+    pub state: Option<(u32, usize)>,
 }
 
 impl Default for Entity {
     fn default() -> Self {
         Entity {
             pos: Vec2::ZERO,
-            draw_condition: &|_, _, _| true,
+            conditions: Vec::new(),
+            effects: Vec::new(),
             draw_type: DrawType::None,
             anim_frame: 0,
+            animation_tag: None,
+            area: Area::Radius(32.0),
+            typewriter: Typewriter::default(),
+            voice: None,
+            state: None,
         }
     }
 }
 
 impl Entity {
-    pub fn draw(&mut self, player: &mut Player, assets: &Assets) {
-        if (self.draw_condition)(self, player, assets) {
+    pub fn in_range(&self, player: &Player) -> bool {
+        match &self.area {
+            // an `interact` slice in the sprite takes over from the default radius
+            Area::Radius(radius) => match self.sprite_rect("interact") {
+                Some(rect) => rect.overlaps(&player.hitbox()),
+                None => player.pos.distance(self.pos) <= *radius,
+            },
+            Area::Object(object) => object.contains(player.pos.x, player.pos.y),
+        }
+    }
+    /// Top left of the sprite, which is drawn with its pivot (or center) at `pos`
+    fn sprite_pos(&self, animation: &Animation) -> Vec2 {
+        self.pos - animation.pivot.unwrap_or(animation.size / 2.0)
+    }
+    /// Area of a slice of the entity's sprite, in the world
+    fn sprite_rect(&self, name: &str) -> Option<Rect> {
+        let DrawType::Animation(animation) = &self.draw_type else {
+            return None;
+        };
+        let rect = animation.slice(name)?;
+        Some(rect.offset(self.sprite_pos(animation)))
+    }
+    /// Starts playing a tag of the entity's animation from the beginning
+    pub fn play(&mut self, tag: &str) {
+        self.animation_tag = Some(tag.to_string());
+        self.anim_frame = 0;
+    }
+    /// Whether the current animation tag has finished playing, always false for looping ones
+    pub fn animation_finished(&self) -> bool {
+        match (&self.draw_type, &self.animation_tag) {
+            (DrawType::Animation(animation), Some(tag)) => {
+                animation.is_finished(tag, self.anim_frame)
+            }
+            _ => false,
+        }
+    }
+    /// Whether the entity's text bubble has been revealed all the way. Always true if it has none.
+    pub fn text_revealed(&self) -> bool {
+        match &self.draw_type {
+            DrawType::TextBubble(text) => self.typewriter.is_finished(text),
+            _ => true,
+        }
+    }
+    pub fn check(&self, condition: &Condition, player: &Player) -> bool {
+        match condition {
+            Condition::Has(tag) => player.tags.contains(tag),
+            Condition::Lacks(tag) => !player.tags.contains(tag),
+            Condition::InRange => self.in_range(player),
+            Condition::OutOfRange => !self.in_range(player),
+            Condition::Started => self.anim_frame > 0,
+            Condition::Playing(tag) => self.animation_tag.as_ref() == Some(tag),
+            Condition::Finished(tag) => {
+                self.animation_tag.as_ref() == Some(tag) && self.animation_finished()
+            }
+            Condition::Any(conditions) => conditions.iter().any(|f| self.check(f, player)),
+            Condition::All(conditions) => conditions.iter().all(|f| self.check(f, player)),
+        }
+    }
+    /// Does an effect. Returns the name of a conversation if it starts one.
+    pub fn apply(
+        &mut self,
+        effect: &Effect,
+        player: &mut Player,
+        assets: &Assets,
+        input: &Input,
+    ) -> Option<String> {
+        match effect {
+            Effect::Grant(tag) => player.grant(tag.clone()),
+            Effect::OpenDoor(name) => player.grant(Tag::DoorOpened(name.clone())),
+            Effect::Play(tag) => self.play(tag),
+            Effect::Tooltip(key, tag) => {
+                if self.text_revealed() && show_tooltip(key, assets, player, input) {
+                    player.grant(tag.clone());
+                }
+            }
+            Effect::Talk(key, conversation) => {
+                // in a conversation this goes straight on to the new one
+                if player.talking
+                    || self.text_revealed() && show_tooltip(key, assets, player, input)
+                {
+                    return Some(conversation.clone());
+                }
+            }
+            Effect::If(conditions, effects) => {
+                if conditions.iter().all(|f| self.check(f, player)) {
+                    let mut talk = None;
+                    for effect in effects {
+                        talk = talk.or(self.apply(effect, player, assets, input));
+                    }
+                    return talk;
+                }
+            }
+        }
+        None
+    }
+    /// Draws the entity if its conditions hold. Returns the name of a conversation
+    /// if the player starts one with it.
+    pub fn draw(&mut self, player: &mut Player, assets: &Assets, input: &Input) -> Option<String> {
+        let mut talk = None;
+        if self.conditions.iter().all(|f| self.check(f, player)) {
+            // the world waits while the player is talking
+            if !player.talking {
+                // taken out while they're applied, since they can change the entity
+                let effects = std::mem::take(&mut self.effects);
+                for effect in effects.iter() {
+                    talk = talk.or(self.apply(effect, player, assets, input));
+                }
+                self.effects = effects;
+            }
             match &self.draw_type {
                 DrawType::None => {}
                 DrawType::Animation(animation) => {
-                    let texture = animation.get_at_time(self.anim_frame);
-                    draw_texture(
-                        texture,
-                        self.pos.x - texture.width() / 2.0,
-                        self.pos.y - texture.height() / 2.0,
-                        WHITE,
-                    );
+                    let frame = match &self.animation_tag {
+                        Some(tag) => animation.tag_frame_at_time(tag, self.anim_frame),
+                        None => animation.frame_at_time(self.anim_frame),
+                    };
+                    let pos = self.sprite_pos(animation);
+                    animation.draw_frame(frame, pos.x, pos.y, &DrawTextureParams::default());
                 }
                 DrawType::TextBubble(text) => {
-                    let lines = text.lines();
-                    let mut width = 0;
-                    for line in lines.clone() {
-                        let len = line.trim().len();
-                        if len > width {
-                            width = len;
-                        }
+                    // not `Confirm`, since space jumps
+                    if input.pressed(Action::Interact) || is_key_pressed(KeyCode::Enter) {
+                        self.typewriter.skip(text);
                     }
+                    self.typewriter.update(text, 1000.0 / 60.0, |char| {
+                        if let Some(voice) = self.voice {
+                            voice(char);
+                        }
+                    });
                     let vertical_offset = 16.0;
                     let padding = 4.0;
-                    let height = lines.clone().count() as f32 * 5.0 + padding * 2.0;
-                    let width = width as f32 * 4.0 + padding * 2.0;
-                    draw_rectangle(
+                    let max_width = 96.0;
+                    let size = assets.measure_text(&text.text, max_width);
+                    let height = size.y + padding * 2.0;
+                    let width = size.x + padding * 2.0;
+                    assets.bubble_panel.draw(
                         self.pos.x,
                         self.pos.y - vertical_offset,
                         width,
                         height,
-                        WHITE,
                     );
-                    assets.draw_text(
+                    assets.draw_text_revealed(
                         text,
                         self.pos.x + padding,
                         self.pos.y + padding - vertical_offset,
+                        max_width,
+                        self.typewriter.revealed(),
                     );
                 }
             }
             self.anim_frame += 1000 / 60;
+        } else {
+            // type the text out again next time it's shown
+            self.typewriter.reset();
         }
+        talk
     }
 }
 
-pub fn show_tooltip(text: &str, grants_tag: Tag, assets: &Assets, player: &mut Player) {
+/// Shows the string at `key` above the player. Returns whether they pressed the interact key.
+pub fn show_tooltip(key: &str, assets: &Assets, player: &Player, input: &Input) -> bool {
     let padding = 2.0;
     let margin = 2.0;
 
-    let width = text.len() as f32 * 4.0 + padding * 2.0;
-    let height = 5.0 + padding * 2.0;
+    let text = markup(&assets.locale.get(key, &[("key", &key_name(INTERACT_KEY))]));
+    let size = assets.measure_text(&text.text, f32::INFINITY);
+    let width = size.x + padding * 2.0;
+    let height = size.y + padding * 2.0;
     let x = (player.camera_pos.x - width / 2.0 + 4.0).floor();
     let y = (player.camera_pos.y - height - margin + SCREEN_HEIGHT / 2.0).floor();
-    draw_rectangle(x, y, width, height, Color::from_hex(0x3b1725));
-    draw_rectangle(
-        x + 1.0,
-        y + 1.0,
-        width - 2.0,
-        height - 2.0,
-        Color::from_hex(0xfffc40),
-    );
-    assets.draw_text(text, x + padding, y + padding);
-    if is_key_pressed(KeyCode::E) {
-        player.tags.push(grants_tag);
-    }
+    assets.tooltip_panel.draw(x, y, width, height);
+    assets.draw_text(&text, x + padding, y + padding, f32::INFINITY);
+    input.pressed(Action::Interact)
 }
 
-pub fn get_entities(world: &World) -> Vec<Entity> {
-    vec![
-        Entity {
-            pos: world.get_interactable_spawn(64).unwrap(),
-            draw_condition: &|this, player, _| {
-                (!player.tags.contains(&Tag::HasMail)
-                    || player.tags.contains(&Tag::HenryHasOfferedCarrot)
-                    || player.tags.contains(&Tag::MailHasBeenSent))
-                    && player.pos.distance(this.pos) > 32.0
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/poi.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(64).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if !player.tags.contains(&Tag::HasMail) {
-                    if player.pos.distance(this.pos) <= 32.0 {
-                        if player.pos.distance(this.pos) <= 32.0 {
-                            show_tooltip("e: take mail", Tag::HasMail, assets, player);
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "hi!
-                please go to the town
-                and post my mail",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(64).unwrap(),
-            draw_condition: &|this, player, _| {
-                player.pos.distance(this.pos) <= 32.0
-                    && player.tags.contains(&Tag::HasMail)
-                    && !player.tags.contains(&Tag::MailHasBeenSent)
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "thanks! return when
-                you have posted it",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(64).unwrap() + Vec2::new(-4.0, 12.0),
-            draw_condition: &|this, player, _| {
-                if !player.tags.contains(&Tag::HasReturnedToHenry) {
-                    player.tags.push(Tag::HasReturnedToHenry);
-                }
-                if this.anim_frame >= 3750 {
-                    player.tags.push(Tag::HenryHasOfferedCarrot);
-                    this.anim_frame = 3700;
-                }
-                (player.pos.distance(this.pos) <= 32.0 || this.anim_frame > 0)
-                    && player.tags.contains(&Tag::MailHasBeenSent)
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/henry_get_reward.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(64).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if player.tags.contains(&Tag::HenryHasOfferedCarrot) {
-                    if player.pos.distance(this.pos) <= 32.0 {
-                        if player.pos.distance(this.pos) <= 32.0 {
-                            show_tooltip("e: accept carrot", Tag::HasCarrot, assets, player);
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "take this carrot
-                as a reward!",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(128).unwrap(),
-            draw_condition: &|this, player, _| {
-                player.pos.distance(this.pos) > 32.0
-                    && (!player.tags.contains(&Tag::HasBirdFood)
-                        || (player.tags.contains(&Tag::HasFedBird)
-                            && !player.tags.contains(&Tag::TonyHasOpenedDoor))
-                        || player.tags.contains(&Tag::HasBeeninGiftStore)
-                            && !player.tags.contains(&Tag::HasMilk))
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/poi.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(128).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if !player.tags.contains(&Tag::HasBirdFood) {
-                    if player.pos.distance(this.pos) <= 32.0 {
-                        if player.pos.distance(this.pos) <= 32.0 {
-                            show_tooltip("e: take bird food", Tag::HasBirdFood, assets, player);
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "hi!
-                feed the bird on my roof
-                and i will let you pass 
-                through here",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(128).unwrap(),
-            draw_condition: &|this, player, _| {
-                player.tags.contains(&Tag::HasBirdFood)
-                    && !player.tags.contains(&Tag::HasFedBird)
-                    && player.pos.distance(this.pos) <= 32.0
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "return when youve fed
-                the bird on my roof",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(128).unwrap(),
-            draw_condition: &|this, player, _| {
-                if !player.tags.contains(&Tag::HasMilk)
-                    && player.tags.contains(&Tag::HasFedBird)
-                    && player.pos.distance(this.pos) <= 32.0
-                {
-                    player.tags.push(Tag::TonyHasOpenedDoor);
-                    true
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from("thanks!")),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(612).unwrap(),
-            draw_condition: &|_, player, _| !player.tags.contains(&Tag::HasFedBird),
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/bird.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(612).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if player.tags.contains(&Tag::HasBirdFood)
-                    && !player.tags.contains(&Tag::HasFedBird)
-                    && player.pos.distance(this.pos) <= 8.0
-                {
-                    show_tooltip("e: feed bird", Tag::HasFedBird, assets, player);
-                }
-                false
-            },
-            draw_type: DrawType::None,
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(612).unwrap(),
-            draw_condition: &|_, player, _| player.tags.contains(&Tag::HasFedBird),
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/bird_eating.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(288).unwrap(),
-            draw_condition: &|this, player, _| {
-                player.tags.contains(&Tag::TonyHasOpenedDoor)
-                    && player.tags.contains(&Tag::HasMail)
-                    && !player.tags.contains(&Tag::HasGivenGift)
-                    && player.pos.distance(this.pos) > 32.0
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/poi.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(288).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if player.tags.contains(&Tag::HasMail) && !player.tags.contains(&Tag::HasGivenGift)
-                {
-                    if player.pos.distance(this.pos) <= 32.0 {
-                        if player.tags.contains(&Tag::HasGift) {
-                            show_tooltip("e: give gift", Tag::HasGivenGift, assets, player);
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "today is my birthday.
-                want to send mail?
-                get me a gift!",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(288).unwrap() + Vec2::new(0.0, 20.0),
-            draw_condition: &|this, player, _| {
-                if this.anim_frame > 650 {
-                    player.tags.push(Tag::MailHasBeenSent);
-                }
-                player.tags.contains(&Tag::HasGivenGift)
-                    && !player.tags.contains(&Tag::MailHasBeenSent)
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/birthday_happy.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(288).unwrap(),
-            draw_condition: &|this, player, _| {
-                if player.tags.contains(&Tag::MailHasBeenSent) {
-                    player.pos.distance(this.pos) <= 32.0
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "thanks for the gift!
-                i have sent your mail",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(384).unwrap(),
-            draw_condition: &|this, player, _| {
-                !player.tags.contains(&Tag::HasGift) && player.pos.distance(this.pos) > 32.0
-            },
-            draw_type: DrawType::Animation(Animation::from_file(include_bytes!(
-                "../assets/entities/poi.ase"
-            ))),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(384).unwrap(),
-            draw_condition: &|this, player, _| {
-                if !player.tags.contains(&Tag::HasMilk) {
-                    if player.pos.distance(this.pos) <= 32.0 {
-                        player.tags.push(Tag::HasBeeninGiftStore);
-                        true
-                    } else {
-                        false
+/// Makes entities for the map's objects:
+/// - `npc` objects are npcs from `npcs.ron`, by name
+/// - `trigger` objects do the effects in their `then` property when the player is inside
+///   them, and the conditions in their `when` property hold
+/// - `poi` objects show a point of interest marker while their `when` property holds
+///
+/// `when` and `then` are written like in `npcs.ron`. Npcs that can't be loaded are an error,
+/// since they could be needed to finish the game, but mistakes in the map are only reported.
+pub fn get_entities(world: &World, locale: &Locale) -> Result<Vec<Entity>, AssetError> {
+    let npcs = load_npcs()?;
+    let mut entities = Vec::new();
+    for object in world.objects.iter() {
+        match object.kind.as_str() {
+            "npc" => {}
+            "trigger" => {
+                let mut conditions = vec![Condition::InRange];
+                conditions.extend(ron_property::<Vec<Condition>>(world, object, "when"));
+                entities.push(Entity {
+                    pos: Vec2::new(object.x, object.y),
+                    conditions,
+                    effects: ron_property(world, object, "then"),
+                    area: npc_area(object, 8.0),
+                    ..Default::default()
+                });
+                continue;
+            }
+            "poi" => {
+                let animation = match Animation::from_file(POI) {
+                    Ok(animation) => animation,
+                    Err(err) => {
+                        eprintln!("can't show poi {:?}: {err}", object.name);
+                        continue;
                     }
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "buy me some milk from
-                tonys grocery and i will
-                give you a gift to give",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(384).unwrap(),
-            draw_condition: &|this, player, _| {
-                player.tags.contains(&Tag::HasGift) && player.pos.distance(this.pos) <= 32.0
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "pleasure doin business
-                with you!",
-            )),
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(384).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if player.tags.contains(&Tag::HasMilk)
-                    && !player.tags.contains(&Tag::HasGift)
-                    && player.pos.distance(this.pos) <= 32.0
-                {
-                    show_tooltip("e: give milk", Tag::SelectingGift, assets, player);
-                }
-                false
-            },
-            draw_type: DrawType::None,
-            ..Default::default()
-        },
-        Entity {
-            pos: world.get_interactable_spawn(128).unwrap(),
-            draw_condition: &|this, player, assets| {
-                if player.tags.contains(&Tag::HasBeeninGiftStore)
-                    && !player.tags.contains(&Tag::HasMilk)
-                    && player.pos.distance(this.pos) <= 32.0
-                {
-                    show_tooltip("e: accept milk", Tag::HasMilk, assets, player);
-                    true
-                } else {
-                    false
-                }
-            },
-            draw_type: DrawType::TextBubble(String::from(
-                "here! have some milk
-                as thanks for feeding
-                my bird",
-            )),
-            ..Default::default()
-        },
-    ]
+                };
+                entities.push(Entity {
+                    pos: Vec2::new(object.x, object.y),
+                    conditions: ron_property(world, object, "when"),
+                    draw_type: DrawType::Animation(animation),
+                    ..Default::default()
+                });
+                continue;
+            }
+            // used by the world itself
+            "door" | "portal" | "spawn" => continue,
+            kind => {
+                eprintln!(
+                    "map {:?} has object {:?} of unknown type {kind:?}",
+                    world.name, object.name
+                );
+                continue;
+            }
+        }
+        let Some(npc) = npcs.get(&object.name) else {
+            eprintln!(
+                "map {:?} has an npc {:?} that isn't in npcs.ron",
+                world.name, object.name
+            );
+            continue;
+        };
+        let pos = Vec2::new(object.x, object.y);
+        let area = npc_area(object, npc.radius);
+        // npcs can be recoloured with a `palette` property, naming a file in `palettes/`
+        let palette = object
+            .properties
+            .get::<String>("palette")
+            .and_then(|f| load_palette(&f));
+        for (index, state) in npc.states.iter().enumerate() {
+            let mut entity = state_entity(state, locale).map_err(|err| {
+                AssetError::new("npcs.ron", format!("npc {:?}: {err}", object.name))
+            })?;
+            entity.pos += pos;
+            entity.area = area.clone();
+            entity.state = Some((object.id, index));
+            entity.voice = npc.speaker.as_deref().and_then(voice);
+            if let DrawType::Animation(animation) = &mut entity.draw_type {
+                animation.palette = palette.clone();
+            }
+            entities.push(entity);
+        }
+    }
+    Ok(entities)
+}
+
+/// Point of interest marker, shown over things the player can do something with
+const POI: &str = "entities/poi.ase";
+
+/// Reads a property written in ron, like the conditions of a trigger. A missing property
+/// is empty, and one that can't be read is reported and treated as empty.
+fn ron_property<T: DeserializeOwned + Default>(world: &World, object: &Object, name: &str) -> T {
+    let Some(value) = object.properties.get::<String>(name) else {
+        return T::default();
+    };
+    ron::from_str(&value).unwrap_or_else(|err| {
+        eprintln!(
+            "map {:?} has object {:?} with a bad {name:?} property: {err}",
+            world.name, object.name
+        );
+        T::default()
+    })
+}
+
+/// Points use a circle around the npc, anything else uses the drawn shape.
+/// The radius can be overridden with a `radius` property.
+fn npc_area(object: &Object, default_radius: f32) -> Area {
+    match object.shape {
+        Shape::Point => Area::Radius(object.properties.get("radius").unwrap_or(default_radius)),
+        _ => Area::Object(object.clone()),
+    }
+}
+
+/// An entity for one of an npc's states, positioned relative to the npc. Fails if its
+/// animation can't be loaded or doesn't have the tags the state uses.
+fn state_entity(state: &State, locale: &Locale) -> Result<Entity, AssetError> {
+    let (draw_type, animation_tag) = match &state.show {
+        Show::Nothing => (DrawType::None, None),
+        Show::Animation { file, tag } => {
+            let animation = Animation::from_file(file)?;
+            // checked now, since playing a tag that doesn't exist panics
+            if let Some(missing) = state
+                .animation_tags()
+                .into_iter()
+                .find(|f| !animation.has_tag(f))
+            {
+                return Err(AssetError::new(file, format!("has no tag {missing:?}")));
+            }
+            (DrawType::Animation(animation), tag.clone())
+        }
+        Show::Bubble(key) => (DrawType::TextBubble(markup(&locale.get(key, &[]))), None),
+    };
+    Ok(Entity {
+        pos: Vec2::from(state.offset),
+        conditions: state.when.clone(),
+        effects: state.then.clone(),
+        draw_type,
+        animation_tag,
+        typewriter: state.char_delay.map(Typewriter::new).unwrap_or_default(),
+        ..Default::default()
+    })
 }
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+/// Directory assets are loaded from at runtime by default, relative to the working directory
+const ASSETS_DIR: &str = "assets";
+
+/// Directory to read assets from at runtime, if any. Set with the `MAIL_ASSETS` environment
+/// variable, otherwise it's `ASSETS_DIR` in debug builds and on the web, and release builds
+/// only use their embedded copies.
+static DIR: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("MAIL_ASSETS").ok().or_else(|| {
+        (cfg!(debug_assertions) || cfg!(target_arch = "wasm32")).then(|| ASSETS_DIR.to_string())
+    })
+});
+
+macro_rules! embed {
+    ($($path:literal),* $(,)?) => {
+        &[$(($path, include_bytes!(concat!("../assets/", $path)))),*]
+    };
+}
+
+/// Every asset file, built into the game so it still runs without an assets directory.
+/// Paths are relative to `ASSETS_DIR`.
+static EMBEDDED: &[(&str, &[u8])] = embed!(
+    "arrow.ase",
+    "dialogue.ron",
+    "font.ase",
+    "font.txt",
+    "gift_selection_screen.ase",
+    "gifts.ase",
+    "start_button.ase",
+    "tileset.ase",
+    "win_screen.ase",
+    "entities/bird.ase",
+    "entities/bird_eating.ase",
+    "entities/birthday_happy.ase",
+    "entities/henry_get_reward.ase",
+    "entities/poi.ase",
+    "entities/player/idle.ase",
+    "entities/player/walk.ase",
+    "lang/en.txt",
+    "lang/sv.txt",
+    "npcs.ron",
+    "palettes/deuteranopia.ase",
+    "ui/bubble.ase",
+    "ui/tooltip.ase",
+    "tilemap/tileset.tsx",
+    "tilemap/tony_storeroom.tmx",
+    "tilemap/world.tmx",
+);
+
+/// Files read by `load_files`, by path
+static LOADED: LazyLock<Mutex<HashMap<String, Vec<u8>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Reads every embedded asset from `DIR`, so they can be changed without recompiling.
+/// On the web this fetches them from the server instead. Files that can't be read
+/// keep using their embedded copy.
+pub async fn load_files() {
+    let Some(dir) = DIR.as_ref() else {
+        return;
+    };
+    for (path, _) in EMBEDDED {
+        if let Ok(bytes) = macroquad::file::load_file(&format!("{dir}/{path}")).await {
+            LOADED.lock().unwrap().insert(path.to_string(), bytes);
+        }
+    }
+}
+
+/// Contents of an asset file, preferring the copy read by `load_files`. Files that
+/// aren't embedded, like a new map, are read from `DIR` on native builds.
+pub fn file(path: &str) -> Option<Vec<u8>> {
+    if let Some(bytes) = LOADED.lock().unwrap().get(path) {
+        return Some(bytes.clone());
+    }
+    if let Some((_, bytes)) = EMBEDDED.iter().find(|f| f.0 == path) {
+        return Some(bytes.to_vec());
+    }
+    let bytes = std::fs::read(format!("{}/{path}", DIR.as_ref()?)).ok()?;
+    LOADED
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), bytes.clone());
+    Some(bytes)
+}
+
+/// Resolves a path relative to the directory `base` is in, like a tileset path in a map
+pub fn relative_to(base: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in path.split('/') {
+        match part {
+            "." | "" => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Notices when asset files change on disk, so they can be reloaded while the game runs.
+/// Only does anything in native debug builds.
+pub struct Watcher {
+    /// When each file in the assets directory was last modified, including new ones
+    modified: HashMap<String, SystemTime>,
+    last_check: f64,
+}
+impl Watcher {
+    const ENABLED: bool = cfg!(all(debug_assertions, not(target_arch = "wasm32")));
+    /// How often to check for changes, in seconds
+    const INTERVAL: f64 = 0.5;
+
+    pub fn new() -> Self {
+        Self {
+            modified: if Self::ENABLED {
+                modified_times()
+            } else {
+                HashMap::new()
+            },
+            last_check: 0.0,
+        }
+    }
+    /// Rereads files that have changed or been added since the last call, and returns their paths
+    pub fn changed_files(&mut self) -> Vec<String> {
+        let now = macroquad::time::get_time();
+        let Some(dir) = DIR.as_ref() else {
+            return Vec::new();
+        };
+        if !Self::ENABLED || now - self.last_check < Self::INTERVAL {
+            return Vec::new();
+        }
+        self.last_check = now;
+
+        let mut changed = Vec::new();
+        for (path, modified) in modified_times() {
+            if self.modified.get(&path) == Some(&modified) {
+                continue;
+            }
+            // reading can fail while the file is being written, in which case try again next time
+            let Ok(bytes) = std::fs::read(format!("{dir}/{path}")) else {
+                continue;
+            };
+            self.modified.insert(path.clone(), modified);
+            LOADED.lock().unwrap().insert(path.clone(), bytes);
+            changed.push(path);
+        }
+        changed
+    }
+}
+
+/// When each file in the assets directory was last modified, by path
+fn modified_times() -> HashMap<String, SystemTime> {
+    let mut times = HashMap::new();
+    let Some(dir) = DIR.as_ref() else {
+        return times;
+    };
+    let mut folders = vec![String::new()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = std::fs::read_dir(format!("{dir}/{folder}")) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = format!("{folder}{}", entry.file_name().to_string_lossy());
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                folders.push(format!("{path}/"));
+            } else if let Ok(modified) = metadata.modified() {
+                times.insert(path, modified);
+            }
+        }
+    }
+    times
+}
@@ -0,0 +1,86 @@
+use macroquad::prelude::*;
+
+use crate::utils::INTERACT_KEY;
+
+/// Something the player does in menus, or to interact with the world
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Up,
+    Down,
+    Confirm,
+    /// Talking to npcs and taking things. Not `Confirm`, since space jumps.
+    Interact,
+}
+impl Action {
+    fn keys(self) -> &'static [KeyCode] {
+        match self {
+            Action::Up => &[KeyCode::W, KeyCode::Up],
+            Action::Down => &[KeyCode::S, KeyCode::Down],
+            Action::Confirm => &[INTERACT_KEY, KeyCode::Enter, KeyCode::Space],
+            Action::Interact => &[INTERACT_KEY],
+        }
+    }
+}
+
+/// Input from the keyboard, and from gamepads with the `gamepad` feature
+pub struct Input {
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<gilrs::Gilrs>,
+    /// Actions pressed on a gamepad this frame
+    pressed: Vec<Action>,
+    /// Which way the left stick is pushed, -1 for down and 1 for up, so holding it
+    /// only counts once
+    #[cfg(feature = "gamepad")]
+    stick: i8,
+}
+impl Input {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(feature = "gamepad")]
+            gilrs: gilrs::Gilrs::new()
+                .inspect_err(|err| eprintln!("can't read gamepads: {err}"))
+                .ok(),
+            pressed: Vec::new(),
+            #[cfg(feature = "gamepad")]
+            stick: 0,
+        }
+    }
+    /// Reads what's been pressed on gamepads since last frame
+    pub fn update(&mut self) {
+        self.pressed.clear();
+        #[cfg(feature = "gamepad")]
+        if let Some(gilrs) = &mut self.gilrs {
+            use gilrs::{Axis, Button, EventType};
+            while let Some(event) = gilrs.next_event() {
+                let actions: &[Action] = match event.event {
+                    EventType::ButtonPressed(Button::DPadUp, _) => &[Action::Up],
+                    EventType::ButtonPressed(Button::DPadDown, _) => &[Action::Down],
+                    EventType::ButtonPressed(Button::South, _) => {
+                        &[Action::Confirm, Action::Interact]
+                    }
+                    EventType::AxisChanged(Axis::LeftStickY, value, _) => {
+                        let stick = if value > 0.5 {
+                            1
+                        } else if value < -0.5 {
+                            -1
+                        } else {
+                            0
+                        };
+                        let moved = stick != self.stick;
+                        self.stick = stick;
+                        match stick {
+                            1 if moved => &[Action::Up],
+                            -1 if moved => &[Action::Down],
+                            _ => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                self.pressed.extend_from_slice(actions);
+            }
+        }
+    }
+    pub fn pressed(&self, action: Action) -> bool {
+        action.keys().iter().any(|f| is_key_pressed(*f)) || self.pressed.contains(&action)
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use crate::files::file;
+
+/// Language strings are taken from when the current language doesn't have them
+const FALLBACK_LANGUAGE: &str = "en";
+
+/// Languages there are strings for, in the order the language setting goes through them
+pub const LANGUAGES: &[&str] = &["en", "sv"];
+
+/// The language to start in, like `sv`. Set with the `MAIL_LANG` environment variable,
+/// otherwise taken from `LANG`. Defaults to English. It can be changed in game too.
+pub fn language_setting() -> String {
+    std::env::var("MAIL_LANG")
+        .ok()
+        .filter(|f| !f.is_empty())
+        .or_else(|| language_from_locale(&std::env::var("LANG").ok()?))
+        .unwrap_or(FALLBACK_LANGUAGE.to_string())
+}
+
+/// The language part of a locale like `sv_SE.UTF-8`. The `C` and `POSIX` locales don't have one
+fn language_from_locale(locale: &str) -> Option<String> {
+    let language = locale.split(['_', '.', '@']).next()?;
+    let known = !language.is_empty() && language != "C" && language != "POSIX";
+    known.then(|| language.to_lowercase())
+}
+
+/// The plural form `count` uses in `language`. Only knows the rules of the languages in
+/// `LANGUAGES` and a few others, the rest use the english one.
+fn plural_form(language: &str, count: u32) -> &'static str {
+    match language {
+        "ja" | "ko" | "zh" => "other",
+        "fr" if count <= 1 => "one",
+        "pl" if count == 1 => "one",
+        "pl" if (2..=4).contains(&(count % 10)) && !(12..=14).contains(&(count % 100)) => "few",
+        "pl" => "many",
+        _ if count == 1 => "one",
+        _ => "other",
+    }
+}
+
+/// Every string the player sees, in one language. They're read from `lang/<language>.txt`,
+/// which has a `key = value` on each line. Values can use variables like `{$key}`, and
+/// markup (see `RichText`).
+///
+/// Strings that depend on a number have a key for each plural form the language has,
+/// like `gifts.one` and `gifts.other` (see `plural_form`).
+pub struct Locale {
+    pub language: String,
+    strings: HashMap<String, String>,
+    fallback: HashMap<String, String>,
+    /// Keys that have already been reported missing, so they're only reported once
+    reported: RefCell<HashSet<String>>,
+}
+impl Locale {
+    pub fn load(language: &str) -> Self {
+        let fallback = read_strings(FALLBACK_LANGUAGE);
+        let strings = if language == FALLBACK_LANGUAGE {
+            HashMap::new()
+        } else {
+            read_strings(language)
+        };
+
+        // report what's left to translate
+        if !strings.is_empty() {
+            let mut missing: Vec<&String> = fallback
+                .keys()
+                .filter(|f| !strings.contains_key(*f))
+                .collect();
+            missing.sort();
+            for key in missing {
+                eprintln!("{language}: missing {key:?}, using {FALLBACK_LANGUAGE}");
+            }
+        }
+        Self {
+            language: language.to_string(),
+            strings,
+            fallback,
+            reported: RefCell::default(),
+        }
+    }
+    /// The string for `key`, with `vars` put in place of its variables
+    pub fn get(&self, key: &str, vars: &[(&str, &str)]) -> String {
+        let Some(string) = self.strings.get(key).or(self.fallback.get(key)) else {
+            if self.reported.borrow_mut().insert(key.to_string()) {
+                eprintln!("missing string {key:?}");
+            }
+            return key.to_string();
+        };
+        let mut string = string.clone();
+        for (name, value) in vars {
+            string = string.replace(&format!("{{${name}}}"), value);
+        }
+        string
+    }
+    /// Same as `get`, but picks the plural form for `count`, which is also the `{$count}` variable
+    pub fn plural(&self, key: &str, count: u32, vars: &[(&str, &str)]) -> String {
+        let form = format!("{key}.{}", plural_form(&self.language, count));
+        // english doesn't have every form other languages do
+        let form = if self.strings.contains_key(&form) {
+            form
+        } else {
+            format!("{key}.{}", plural_form(FALLBACK_LANGUAGE, count))
+        };
+        let count = count.to_string();
+        let mut vars = vars.to_vec();
+        vars.push(("count", &count));
+        self.get(&form, &vars)
+    }
+}
+
+fn read_strings(language: &str) -> HashMap<String, String> {
+    let path = format!("lang/{language}.txt");
+    let Some(bytes) = file(&path) else {
+        eprintln!("no strings for language {language:?}, using {FALLBACK_LANGUAGE}");
+        return HashMap::new();
+    };
+    let mut strings = HashMap::new();
+    for (index, line) in String::from_utf8_lossy(&bytes).lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => {
+                strings.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            None => eprintln!("{path}:{}: expected `key = value`", index + 1),
+        }
+    }
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_from_lang() {
+        assert_eq!(language_from_locale("sv_SE.UTF-8").as_deref(), Some("sv"));
+        assert_eq!(language_from_locale("en").as_deref(), Some("en"));
+        assert_eq!(language_from_locale("fil_PH").as_deref(), Some("fil"));
+        assert_eq!(
+            language_from_locale("ca_ES@valencia").as_deref(),
+            Some("ca")
+        );
+        assert_eq!(language_from_locale("C.UTF-8"), None);
+        assert_eq!(language_from_locale("C"), None);
+        assert_eq!(language_from_locale("POSIX"), None);
+        assert_eq!(language_from_locale(""), None);
+    }
+
+    #[test]
+    fn plural_forms() {
+        let forms = |language| [0, 1, 2, 5, 12, 22].map(|f| plural_form(language, f));
+        let english = ["other", "one", "other", "other", "other", "other"];
+        assert_eq!(forms("en"), english);
+        assert_eq!(forms("sv"), english);
+        assert_eq!(
+            forms("fr"),
+            ["one", "one", "other", "other", "other", "other"]
+        );
+        assert_eq!(forms("pl"), ["many", "one", "few", "many", "many", "few"]);
+        assert_eq!(forms("ja"), ["other"; 6]);
+    }
+}
@@ -1,15 +1,30 @@
+use std::collections::HashMap;
+
 use macroquad::{miniquad::window::screen_size, prelude::*, time};
 
 use crate::{
     assets::*,
+    dialogue::Dialogue,
     entities::{get_entities, show_tooltip},
+    files::{Watcher, load_files},
+    input::Input,
+    locale::{LANGUAGES, Locale, language_setting},
     player::*,
+    text::markup,
     utils::*,
 };
 
 mod assets;
+mod atlas;
+mod dialogue;
 mod entities;
+mod files;
+mod input;
+mod locale;
+mod npcs;
 mod player;
+mod text;
+mod tiled;
 mod utils;
 
 fn window_conf() -> Conf {
@@ -22,10 +37,16 @@ fn window_conf() -> Conf {
 }
 #[macroquad::main(window_conf)]
 async fn main() {
-    let assets = Assets::default();
+    load_files().await;
+    let mut assets =
+        Assets::load(&language_setting()).unwrap_or_else(|err| panic!("failed to load {err}"));
     let mut pixel_camera = create_camera(SCREEN_WIDTH, SCREEN_HEIGHT);
-    let mut world = World::default();
-    let mut entities = get_entities(&world);
+    let mut world = World::load("world").unwrap_or_else(|err| {
+        eprintln!("failed to load map \"world\": {err}");
+        World::empty("world")
+    });
+    let mut entities =
+        get_entities(&world, &assets.locale).unwrap_or_else(|err| panic!("failed to load {err}"));
     let mut player = Player::new();
 
     player.pos = Vec2::new(-6.0 * 8.0, 2.0 * 8.0 - 20.0 * 8.0);
@@ -36,7 +57,58 @@ async fn main() {
 
     let mut gift_select_index = 0;
 
+    let mut input = Input::new();
+    let mut dialogue: Option<Dialogue> = None;
+
+    let mut transition: Option<Transition> = None;
+    // so arriving on top of a portal doesn't immediately send the player back
+    let mut in_portal = false;
+
+    let mut watcher = Watcher::new();
+
     loop {
+        input.update();
+        let changed = watcher.changed_files();
+        if !changed.is_empty() {
+            if changed.iter().any(|f| !f.starts_with("tilemap/")) {
+                atlas::clear();
+                // a broken or half saved file keeps the old assets until it's fixed
+                match Assets::load(&assets.locale.language) {
+                    Ok(new) => assets = new,
+                    Err(err) => eprintln!("failed to reload {err}"),
+                }
+                player.reload_animations();
+            }
+            // reload the map even if only sprites changed, so chunks are baked with the new tileset.
+            // the player stays where they are and keeps their tags
+            match World::load(&world.name) {
+                Ok(new) => {
+                    world = new;
+                    // the conversation could have changed too
+                    dialogue = None;
+                    match get_entities(&world, &assets.locale) {
+                        Ok(new) => {
+                            // npcs carry on from where they were, by map object and state,
+                            // since states could have been added or removed
+                            let mut old: HashMap<_, _> = std::mem::replace(&mut entities, new)
+                                .into_iter()
+                                .filter_map(|f| Some((f.state?, f)))
+                                .collect();
+                            for entity in entities.iter_mut() {
+                                let Some(old) = entity.state.and_then(|f| old.remove(&f)) else {
+                                    continue;
+                                };
+                                entity.anim_frame = old.anim_frame;
+                                entity.animation_tag = old.animation_tag;
+                            }
+                        }
+                        Err(err) => eprintln!("failed to reload {err}"),
+                    }
+                }
+                Err(err) => eprintln!("failed to reload map {:?}: {err}", world.name),
+            }
+        }
+
         let (actual_screen_width, actual_screen_height) = screen_size();
         let scale_factor =
             (actual_screen_width / SCREEN_WIDTH).min(actual_screen_height / SCREEN_HEIGHT);
@@ -50,7 +122,7 @@ async fn main() {
             set_camera(&pixel_camera);
             // draw background
             clear_background(Color::from_hex(0x422433));
-            draw_texture(&assets.win_screen, 0.0, 0.0, WHITE);
+            assets.win_screen.draw(0.0, 0.0, WHITE);
         } else if player.tags.contains(&Tag::SelectingGift) && !player.tags.contains(&Tag::HasGift)
         {
             // handle gift selection screen
@@ -58,18 +130,23 @@ async fn main() {
             set_camera(&pixel_camera);
             // draw background
             clear_background(Color::from_hex(0x422433));
-            draw_texture(&assets.gift_selection_screen, 0.0, 0.0, WHITE);
+            assets.gift_selection_screen.draw(0.0, 0.0, WHITE);
 
             // draw text
-            let text = "select a gift";
+            let text = markup(&assets.locale.plural(
+                "gift_select.title",
+                assets.gift_sprites.total_length,
+                &[],
+            ));
             let padding = 2.0;
             let margin = 2.0;
-            let width = text.len() as f32 * 4.0 + padding * 2.0;
-            let height = 5.0 + padding * 2.0;
+            let size = assets.measure_text(&text.text, f32::INFINITY);
+            let width = size.x + padding * 2.0;
+            let height = size.y + padding * 2.0;
             let x = (SCREEN_WIDTH - width) / 2.0;
             let y = margin;
             draw_rectangle(x, y, width, height, WHITE);
-            assets.draw_text(text, x + padding, y + padding);
+            assets.draw_text(&text, x + padding, y + padding, f32::INFINITY);
 
             // draw buttons
             if draw_button(
@@ -106,8 +183,7 @@ async fn main() {
 
             // draw gift selection
             let gift_texture = assets.gift_sprites.get_at_time(gift_select_index);
-            draw_texture(
-                gift_texture,
+            gift_texture.draw(
                 (SCREEN_WIDTH - gift_texture.width()) / 2.0,
                 (SCREEN_HEIGHT - gift_texture.height()) / 2.0,
                 WHITE,
@@ -117,76 +193,153 @@ async fn main() {
             // but since this menu has a fixed pos camera, this needs to be disabled
             let mut old = Vec2::new(SCREEN_WIDTH / 2.0, SCREEN_HEIGHT / 2.0);
             (player.camera_pos, old) = (old, player.camera_pos);
-            show_tooltip("e: select this gift", Tag::HasGift, &assets, &mut player);
+            if show_tooltip("tooltip.select_gift", &assets, &player, &input) {
+                player.grant(Tag::HasGift);
+            }
             player.camera_pos = old;
         } else {
             // draw game!
             let now = time::get_time();
             let started = player.tags.contains(&Tag::GameStarted);
+            player.talking = dialogue.is_some();
 
             if now - last > 1.0 / 60.0 {
                 last = now;
-                if started {
+                if let Some(current) = &mut transition {
+                    current.frame += 1;
+                    if current.frame == Transition::SWITCH_FRAME {
+                        if let Some((new, new_entities)) = current.world.take() {
+                            world = new;
+                            entities = new_entities;
+                        }
+                        player.teleport(current.spawn);
+                        in_portal = true;
+                    }
+                    if current.is_finished() {
+                        transition = None;
+                    }
+                } else if started && !player.talking {
                     player.update(&world);
+                    let portal = world.portal_at(player.pos + Vec2::splat(4.0));
+                    let entered = portal.is_some() && !in_portal;
+                    in_portal = portal.is_some();
+                    // a broken portal leaves the player where they are
+                    if entered && let Some(portal) = portal {
+                        match Transition::new(&portal, &world, &assets.locale) {
+                            Ok(new) => transition = Some(new),
+                            Err(err) => eprintln!("can't go through portal: {err}"),
+                        }
+                    }
                 }
             }
             pixel_camera.target = player.camera_pos;
+            let view = view_rect(&pixel_camera);
+            world.bake_chunks(&assets, view);
             set_camera(&pixel_camera);
 
             clear_background(Color::from_hex(0x249fde));
 
-            for chunk in world.background.iter() {
-                chunk.draw(&assets);
-            }
-            for chunk in world.collision.iter() {
-                chunk.draw(&assets);
-            }
-            for chunk in world.details.iter() {
-                chunk.draw(&assets);
-            }
-            for chunk in world.one_way_collision.iter() {
-                chunk.draw(&assets);
-            }
+            world.draw(&assets, view, false);
 
-            for entity in entities.iter_mut() {
-                entity.draw(&mut player, &assets);
+            let mut talk = None;
+            for (index, entity) in entities.iter_mut().enumerate() {
+                if let Some(conversation) = entity.draw(&mut player, &assets, &input) {
+                    talk = Some((index, conversation));
+                }
             }
 
-            if player.tags.contains(&Tag::TonyHasOpenedDoor) {
-                world.set_collision_tile(79, 1, 0);
-                world.set_collision_tile(79, 2, 0);
+            for tag in player.tags.iter() {
+                if let Tag::DoorOpened(name) = tag {
+                    world.open_door(name);
+                }
             }
 
             if started {
                 player.draw(&assets);
-            } else if draw_button(
-                &assets.start_button,
-                &assets.start_button_hovered,
-                102.0 + player.camera_pos.x - SCREEN_WIDTH / 2.0,
-                68.0 + player.camera_pos.y - SCREEN_HEIGHT / 2.0,
-                mouse_x + player.camera_pos.x - SCREEN_WIDTH / 2.0,
-                mouse_y + player.camera_pos.y - SCREEN_HEIGHT / 2.0,
-                false,
-            ) {
+            }
+            world.draw(&assets, view, true);
+
+            if let Some(current) = &mut dialogue {
+                let entity = &mut entities[current.entity];
+                let mouse = Vec2::new(mouse_x, mouse_y);
+                current.update(&input, mouse, entity, &mut player, &assets);
+                if current.is_finished() {
+                    dialogue = None;
+                } else {
+                    current.draw(&assets, player.camera_pos);
+                }
+            } else if let Some((index, conversation)) = talk {
+                player.talking = true;
+                dialogue = Dialogue::start(
+                    &conversation,
+                    index,
+                    &mut entities[index],
+                    &mut player,
+                    &assets,
+                    &input,
+                );
+            }
+            if !started
+                && draw_button(
+                    &assets.start_button,
+                    &assets.start_button_hovered,
+                    102.0 + player.camera_pos.x - SCREEN_WIDTH / 2.0,
+                    68.0 + player.camera_pos.y - SCREEN_HEIGHT / 2.0,
+                    mouse_x + player.camera_pos.x - SCREEN_WIDTH / 2.0,
+                    mouse_y + player.camera_pos.y - SCREEN_HEIGHT / 2.0,
+                    false,
+                )
+            {
                 player.tags.push(Tag::GameStarted);
             }
+            if !started {
+                if is_key_pressed(LANGUAGE_KEY) {
+                    let current = LANGUAGES.iter().position(|f| *f == assets.locale.language);
+                    let next = current.map_or(0, |f| (f + 1) % LANGUAGES.len());
+                    assets.locale = Locale::load(LANGUAGES[next]);
+                    // npcs keep the text they were made with
+                    match get_entities(&world, &assets.locale) {
+                        Ok(new) => entities = new,
+                        Err(err) => eprintln!("failed to load {err}"),
+                    }
+                }
+                let key = key_name(LANGUAGE_KEY);
+                let text = markup(&assets.locale.get("start.language", &[("key", &key)]));
+                let padding = 2.0;
+                let margin = 2.0;
+                let size = assets.measure_text(&text.text, f32::INFINITY);
+                let x = player.camera_pos.x - SCREEN_WIDTH / 2.0 + margin;
+                let y = player.camera_pos.y + SCREEN_HEIGHT / 2.0 - margin - size.y - padding * 2.0;
+                draw_rectangle(x, y, size.x + padding * 2.0, size.y + padding * 2.0, WHITE);
+                assets.draw_text(&text, x + padding, y + padding, f32::INFINITY);
+            }
+
+            if let Some(transition) = &transition {
+                transition.draw(player.camera_pos);
+            }
         }
 
         set_default_camera();
         clear_background(BLACK);
-        draw_texture_ex(
-            &pixel_camera.render_target.as_ref().unwrap().texture,
-            0.0,
-            0.0,
-            WHITE,
-            DrawTextureParams {
-                dest_size: Some(Vec2::new(
-                    SCREEN_WIDTH * scale_factor,
-                    SCREEN_HEIGHT * scale_factor,
-                )),
-                ..Default::default()
-            },
-        );
+        let draw_screen = || {
+            draw_texture_ex(
+                &pixel_camera.render_target.as_ref().unwrap().texture,
+                0.0,
+                0.0,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(Vec2::new(
+                        SCREEN_WIDTH * scale_factor,
+                        SCREEN_HEIGHT * scale_factor,
+                    )),
+                    ..Default::default()
+                },
+            )
+        };
+        match &assets.palette {
+            Some(palette) => palette.apply(draw_screen),
+            None => draw_screen(),
+        }
         next_frame().await
     }
 }
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{
+    assets::{AssetError, read_asset},
+    player::Tag,
+};
+
+/// Every npc and what they do, read from `npcs.ron`. Npcs are placed in maps as objects
+/// of type `npc`, named after a key in this file.
+pub type Npcs = HashMap<String, Npc>;
+
+pub fn load_npcs() -> Result<Npcs, AssetError> {
+    let path = "npcs.ron";
+    ron::de::from_bytes(&read_asset(path)?).map_err(|err| AssetError::new(path, err))
+}
+
+#[derive(Deserialize)]
+pub struct Npc {
+    /// How close the player has to be to interact, if the map object is a point
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    /// Key of the npc's name, like in `dialogue.ron`. Picks the voice its text bubbles
+    /// are spoken in (see `VOICES`).
+    #[serde(default)]
+    pub speaker: Option<String>,
+    /// Everything drawn for the npc. Each one is drawn whenever its conditions hold,
+    /// so they can be layered, like an icon over a sprite.
+    pub states: Vec<State>,
+}
+fn default_radius() -> f32 {
+    32.0
+}
+
+#[derive(Clone, Deserialize)]
+pub struct State {
+    pub show: Show,
+    /// Relative to the npc's position
+    #[serde(default)]
+    pub offset: (f32, f32),
+    /// Milliseconds between characters of a text bubble, if not the usual
+    #[serde(default)]
+    pub char_delay: Option<f32>,
+    /// Shown while all of these hold
+    #[serde(default)]
+    pub when: Vec<Condition>,
+    /// Done every frame while shown
+    #[serde(default)]
+    pub then: Vec<Effect>,
+}
+impl State {
+    /// Every animation tag the state plays or checks for
+    pub fn animation_tags(&self) -> Vec<&str> {
+        let mut tags = Vec::new();
+        if let Show::Animation { tag: Some(tag), .. } = &self.show {
+            tags.push(tag.as_str());
+        }
+        for condition in self.when.iter() {
+            condition.animation_tags(&mut tags);
+        }
+        for effect in self.then.iter() {
+            effect.animation_tags(&mut tags);
+        }
+        tags
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub enum Show {
+    /// Nothing, for states that only have effects
+    Nothing,
+    Animation {
+        /// Path in the assets directory. Files that aren't embedded in the game (see
+        /// `files::EMBEDDED`) are only found when it's run with an assets directory.
+        file: String,
+        /// Tag to play at first, otherwise the whole file loops
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    /// A text bubble with the string at this key
+    Bubble(String),
+}
+
+#[derive(Clone, Deserialize)]
+pub enum Condition {
+    Has(Tag),
+    Lacks(Tag),
+    /// The player is close enough to interact
+    InRange,
+    OutOfRange,
+    /// The animation has played past its first frame
+    Started,
+    /// This animation tag is playing
+    Playing(String),
+    /// This animation tag is playing and has finished
+    Finished(String),
+    Any(Vec<Condition>),
+    All(Vec<Condition>),
+}
+impl Condition {
+    fn animation_tags<'a>(&'a self, tags: &mut Vec<&'a str>) {
+        match self {
+            Condition::Playing(tag) | Condition::Finished(tag) => tags.push(tag),
+            Condition::Any(conditions) | Condition::All(conditions) => {
+                for condition in conditions {
+                    condition.animation_tags(tags);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub enum Effect {
+    Grant(Tag),
+    /// Opens door objects with this name, for good
+    OpenDoor(String),
+    /// Starts playing an animation tag from the beginning
+    Play(String),
+    /// Shows the string at a key above the player once the text bubble is revealed,
+    /// and grants a tag when they press the interact key
+    Tooltip(String, Tag),
+    /// Shows the string at a key above the player like `Tooltip`, and starts the
+    /// conversation with this name in `dialogue.ron` when they press the interact key
+    Talk(String, String),
+    If(Vec<Condition>, Vec<Effect>),
+}
+impl Effect {
+    fn animation_tags<'a>(&'a self, tags: &mut Vec<&'a str>) {
+        match self {
+            Effect::Play(tag) => tags.push(tag),
+            Effect::If(conditions, effects) => {
+                for condition in conditions {
+                    condition.animation_tags(tags);
+                }
+                for effect in effects {
+                    effect.animation_tags(tags);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,39 +1,43 @@
 use macroquad::prelude::*;
+use serde::Deserialize;
 
-use crate::{assets::*, utils::*};
+use crate::{assets::*, files::file, utils::*};
 
-fn get_tile(chunks: &[&Chunk], x: i16, y: i16) -> i16 {
-    let cx = ((x as f32 / 16.0).floor() * 16.0) as i16;
-    let cy = ((y as f32 / 16.0).floor() * 16.0) as i16;
-    let Some(chunk) = chunks.iter().find(|f| f.x == cx && f.y == cy) else {
-        return 0;
-    };
-    let local_x = x - chunk.x;
-    let local_y = y - chunk.y;
-    chunk.tile_at(local_x as _, local_y as _).unwrap_or(0)
+/// The idle and walk animations. They're recoloured with `palettes/player.ase` if there is one.
+fn load_animations() -> Result<(Animation, Animation), AssetError> {
+    let mut idle = Animation::from_file("entities/player/idle.ase")?;
+    let mut walk = Animation::from_file("entities/player/walk.ase")?;
+    if file("palettes/player.ase").is_some() {
+        idle.palette = load_palette("player");
+        walk.palette = idle.palette.clone();
+    }
+    Ok((idle, walk))
 }
 
 fn ceil_g(a: f32) -> f32 {
     if a < 0.0 { a.floor() } else { a.ceil() }
 }
 
-#[derive(PartialEq)]
+/// Things the player has done. Npcs in `npcs.ron` check and grant these by name.
+#[derive(Clone, PartialEq, Deserialize)]
 pub enum Tag {
     GameStarted,
     StartAnimationFinished,
     HasMail,
     HasBirdFood,
     HasFedBird,
-    TonyHasOpenedDoor,
     HasGift,
     HasGivenGift,
     MailHasBeenSent,
     HasBeeninGiftStore,
     HasMilk,
     SelectingGift,
-    HasReturnedToHenry,
     HenryHasOfferedCarrot,
     HasCarrot,
+    /// A door object with this name has been opened
+    DoorOpened(String),
+    /// Anything else a quest needs to remember, so new ones don't need a variant
+    Quest(String),
 }
 
 pub struct Player {
@@ -45,11 +49,17 @@ pub struct Player {
     pub on_ground: bool,
     pub jump_frames: u8,
     pub tags: Vec<Tag>,
+    /// Whether the player is in a conversation
+    pub talking: bool,
+    /// Where the player last stood on safe ground, used when touching hazards
+    safe_pos: Option<Vec2>,
     idle_animation: Animation,
     walk_animation: Animation,
 }
 impl Player {
     pub fn new() -> Self {
+        let (idle_animation, walk_animation) =
+            load_animations().unwrap_or_else(|err| panic!("failed to load player: {err}"));
         Self {
             pos: Vec2::ZERO,
             camera_pos: Vec2::ZERO,
@@ -59,14 +69,35 @@ impl Player {
             facing_right: true,
             on_ground: false,
             tags: Vec::new(),
-            idle_animation: Animation::from_file(include_bytes!(
-                "../assets/entities/player/idle.ase"
-            )),
-            walk_animation: Animation::from_file(include_bytes!(
-                "../assets/entities/player/walk.ase"
-            )),
+            talking: false,
+            safe_pos: None,
+            idle_animation,
+            walk_animation,
+        }
+    }
+    /// Gives the player a tag, unless they already have it
+    pub fn grant(&mut self, tag: Tag) {
+        if !self.tags.contains(&tag) {
+            self.tags.push(tag);
+        }
+    }
+    /// Rereads the player's animations, for when they change on disk
+    pub fn reload_animations(&mut self) {
+        match load_animations() {
+            Ok((idle, walk)) => {
+                self.idle_animation = idle;
+                self.walk_animation = walk;
+            }
+            Err(err) => eprintln!("failed to reload player animations: {err}"),
         }
     }
+    /// Moves the player so their feet are at `pos`, like when going through a portal
+    pub fn teleport(&mut self, pos: Vec2) {
+        self.pos = pos - Vec2::new(4.0, 8.0);
+        self.camera_pos = self.pos.floor();
+        self.velocity = Vec2::ZERO;
+        self.safe_pos = None;
+    }
     pub fn update(&mut self, world: &World) {
         self.anim_frame += 1000 / 60;
 
@@ -78,10 +109,22 @@ impl Player {
 
         let can_move = self.tags.contains(&Tag::StartAnimationFinished);
 
+        // what the middle of the player is inside of
+        let inside = world.tile_kind(
+            (self.pos.x / 8.0 + 0.5).floor() as i16,
+            (self.pos.y / 8.0 + 0.5).floor() as i16,
+        );
+        let climbing = inside == TileKind::Ladder;
+        let swimming = inside == TileKind::Water;
+
         let mut forces = Vec2::ZERO;
 
-        if !noclip {
-            forces.y += GRAVITY
+        if !noclip && !climbing {
+            forces.y += if swimming {
+                GRAVITY * WATER_GRAVITY
+            } else {
+                GRAVITY
+            };
         }
 
         forces = forces.clamp_length_max(8.0);
@@ -108,6 +151,19 @@ impl Player {
             self.jump_frames += 1;
         }
 
+        if can_move && climbing {
+            self.velocity.y = 0.0;
+            if is_key_down(KeyCode::W) {
+                forces.y -= CLIMB_SPEED;
+            }
+            if is_key_down(KeyCode::S) {
+                forces.y += CLIMB_SPEED;
+            }
+        }
+        if can_move && swimming && is_key_down(KeyCode::Space) {
+            forces.y -= SWIM_FORCE;
+        }
+
         if noclip {
             if is_key_down(KeyCode::W) {
                 forces.y -= 1.0;
@@ -124,11 +180,16 @@ impl Player {
         }
 
         forces.x -= self.velocity.x
-            * if self.on_ground {
+            * if swimming {
+                WATER_DRAG
+            } else if self.on_ground {
                 GROUND_FRICTION
             } else {
                 AIR_DRAG
             };
+        if swimming {
+            forces.y -= self.velocity.y * WATER_DRAG;
+        }
 
         self.velocity += forces;
 
@@ -144,28 +205,11 @@ impl Player {
             (ceil_g(tile_x), (new.y / 8.0).trunc()),
         ];
 
-        let chunks_pos: [(i16, i16); 4] = std::array::from_fn(|f| {
-            let cx = ((tiles_y[f].0 / 16.0).floor() * 16.0) as i16;
-            let cy = ((tiles_y[f].1 / 16.0).floor() * 16.0) as i16;
-            (cx, cy)
-        });
-
-        let chunks: Vec<&Chunk> = world
-            .collision
-            .iter()
-            .filter(|f| chunks_pos.contains(&(f.x, f.y)))
-            .collect();
-
-        let one_way_chunks: Vec<&Chunk> = world
-            .one_way_collision
-            .iter()
-            .filter(|f| chunks_pos.contains(&(f.x, f.y)))
-            .collect();
-
+        let was_on_ground = self.on_ground;
         self.on_ground = false;
         for (tx, ty) in tiles_y {
-            let tile = get_tile(&chunks, tx as i16, ty as i16);
-            if tile != 0 {
+            let kind = world.tile_kind(tx as i16, ty as i16);
+            if kind == TileKind::Solid {
                 let c = if self.velocity.y < 0.0 {
                     tile_y.floor() * 8.0
                 } else {
@@ -178,10 +222,7 @@ impl Player {
             }
 
             // handle single way platforms
-            if self.velocity.y > 0.0
-                && ty.trunc() > tile_y.trunc()
-                && get_tile(&one_way_chunks, tx as i16, ty as i16) != 0
-            {
+            if self.velocity.y > 0.0 && ty.trunc() > tile_y.trunc() && kind == TileKind::OneWay {
                 new.y = tile_y.ceil() * 8.0;
                 self.velocity.y = 0.0;
                 self.on_ground = true;
@@ -195,21 +236,8 @@ impl Player {
             ((new.x / 8.0).trunc(), (new.y / 8.0).trunc()),
         ];
 
-        let chunks_pos: [(i16, i16); 4] = std::array::from_fn(|f| {
-            let cx = ((tiles_x[f].0 / 16.0).floor() * 16.0) as i16;
-            let cy = ((tiles_x[f].1 / 16.0).floor() * 16.0) as i16;
-            (cx, cy)
-        });
-
-        let chunks: Vec<&Chunk> = world
-            .collision
-            .iter()
-            .filter(|f| chunks_pos.contains(&(f.x, f.y)))
-            .collect();
-
         for (tx, ty) in tiles_x {
-            let tile = get_tile(&chunks, tx as i16, ty as i16);
-            if tile != 0 {
+            if world.tile_kind(tx as i16, ty as i16) == TileKind::Solid {
                 let c = if self.velocity.x < 0.0 {
                     tile_x.floor() * 8.0
                 } else {
@@ -221,12 +249,48 @@ impl Player {
             }
         }
 
+        // walk up and down slopes by keeping the feet on the slope's surface
+        let feet = new + Vec2::new(4.0, 8.0);
+        let feet_tile = ((feet.y - 0.01) / 8.0).floor();
+        for ty in [feet_tile, feet_tile + 1.0] {
+            let tx = (feet.x / 8.0).floor();
+            let TileKind::Slope { left, right } = world.tile_kind(tx as i16, ty as i16) else {
+                continue;
+            };
+            let t = feet.x / 8.0 - tx;
+            let surface = (ty + 1.0) * 8.0 - (left + (right - left) * t);
+            // stick to the slope when walking down it, instead of bouncing off
+            if self.velocity.y >= 0.0
+                && (feet.y > surface || (was_on_ground && surface - feet.y <= 4.0))
+            {
+                new.y = surface - 8.0;
+                self.velocity.y = 0.0;
+                self.on_ground = true;
+                break;
+            }
+        }
+
         if self.velocity.x.abs() <= 0.3 {
             self.velocity.x = 0.0;
         }
         self.velocity.x = self.velocity.x.clamp(-MAX_VELOCITY, MAX_VELOCITY);
         self.pos = new;
 
+        let hitbox = self.hitbox();
+        let min = (hitbox.point() / 8.0).floor();
+        let max = ((hitbox.point() + hitbox.size() - 0.01) / 8.0).floor();
+        let touching_hazard = (min.y as i16..=max.y as i16).any(|y| {
+            (min.x as i16..=max.x as i16).any(|x| world.tile_kind(x, y) == TileKind::Hazard)
+        });
+        if touching_hazard {
+            if let Some(safe_pos) = self.safe_pos {
+                self.pos = safe_pos;
+                self.velocity = Vec2::ZERO;
+            }
+        } else if self.on_ground {
+            self.safe_pos = Some(self.pos);
+        }
+
         if self.pos.y >= 2.0 * 8.0 && !can_move {
             self.tags.push(Tag::StartAnimationFinished);
         }
@@ -238,18 +302,45 @@ impl Player {
                 max_delta * if delta < 0.0 { -1.0 } else { 1.0 } + self.pos.y.floor();
         }
     }
-    pub fn draw(&self, _assets: &Assets) {
-        let animation = if self.velocity.length() != 0.0 {
+    fn animation(&self) -> &Animation {
+        if self.velocity.length() != 0.0 {
             &self.walk_animation
         } else {
             &self.idle_animation
+        }
+    }
+    /// Top left of the sprite. The sprite's pivot is placed at the player's feet,
+    /// or its bottom middle if it has no pivot.
+    fn sprite_pos(&self) -> Vec2 {
+        let animation = self.animation();
+        let mut pivot = animation
+            .pivot
+            .unwrap_or(Vec2::new(animation.size.x / 2.0, animation.size.y));
+        if !self.facing_right {
+            pivot.x = animation.size.x - pivot.x;
+        }
+        self.pos.floor() + Vec2::new(4.0, 8.0) - pivot
+    }
+    /// The area that touches hazards, from the sprite's `hitbox` slice.
+    /// Defaults to the tile sized box the player collides with.
+    pub fn hitbox(&self) -> Rect {
+        let animation = self.animation();
+        let Some(mut rect) = animation.slice("hitbox") else {
+            return Rect::new(self.pos.x, self.pos.y, 8.0, 8.0);
         };
-        draw_texture_ex(
-            animation.get_at_time(self.anim_frame),
-            self.pos.floor().x,
-            self.pos.floor().y,
-            WHITE,
-            DrawTextureParams {
+        if !self.facing_right {
+            rect.x = animation.size.x - rect.right();
+        }
+        rect.offset(self.sprite_pos())
+    }
+    pub fn draw(&self, _assets: &Assets) {
+        let pos = self.sprite_pos();
+        let animation = self.animation();
+        animation.draw_frame(
+            animation.frame_at_time(self.anim_frame),
+            pos.x,
+            pos.y,
+            &DrawTextureParams {
                 flip_x: !self.facing_right,
                 ..Default::default()
             },
@@ -0,0 +1,349 @@
+use std::fmt;
+
+use macroquad::prelude::*;
+
+use crate::utils::TEXT_COLORS;
+
+/// Text with its markup parsed out of it. Markup goes in braces:
+/// - `{red}`, or any other name in `TEXT_COLORS`, changes the colour
+/// - `{wave}` and `{shake}` make the text move
+/// - `{/}` ends the last colour or movement
+/// - `{pause:300}` waits 300ms before the rest of the text, when it's revealed over time
+///
+/// `{{` is a brace on its own.
+pub struct RichText {
+    /// The text without its markup
+    pub text: String,
+    /// Where each style starts in `text`, in bytes. The first one starts at 0.
+    styles: Vec<(usize, Style)>,
+    /// Milliseconds to wait before revealing the character at an index, in bytes
+    pauses: Vec<(usize, f32)>,
+}
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    /// Uses the default text colour if there isn't one
+    pub color: Option<Vec4>,
+    pub motion: Option<Motion>,
+}
+#[derive(Clone, Copy, PartialEq)]
+pub enum Motion {
+    /// Glyphs bob up and down one after another
+    Wave,
+    /// Glyphs jitter in place
+    Shake,
+}
+impl Motion {
+    /// How far a glyph at `x` is moved from where it would be
+    pub fn offset(self, x: f32, time: f64) -> Vec2 {
+        match self {
+            Motion::Wave => Vec2::new(0.0, (time * 8.0 - x as f64 * 0.25).sin().round() as f32),
+            Motion::Shake => {
+                Vec2::new(rand::gen_range(-1, 2) as f32, rand::gen_range(-1, 2) as f32)
+            }
+        }
+    }
+}
+
+impl RichText {
+    pub fn parse(markup: &str) -> Result<Self, MarkupError> {
+        let mut text = String::new();
+        let mut styles = vec![(0, Style::default())];
+        let mut pauses = Vec::new();
+        // styles that haven't been ended yet, the last one is the current style
+        let mut stack = vec![Style::default()];
+        let mut rest = markup;
+
+        while let Some(open) = rest.find('{') {
+            text.push_str(&rest[..open]);
+            rest = &rest[open + 1..];
+            if let Some(after) = rest.strip_prefix('{') {
+                text.push('{');
+                rest = after;
+                continue;
+            }
+            let Some(close) = rest.find('}') else {
+                return Err(MarkupError::Unclosed(rest.to_string()));
+            };
+            let tag = &rest[..close];
+            rest = &rest[close + 1..];
+
+            let current = *stack.last().unwrap();
+            match tag {
+                "/" => {
+                    if stack.len() == 1 {
+                        return Err(MarkupError::NothingToEnd);
+                    }
+                    stack.pop();
+                }
+                "wave" => stack.push(Style {
+                    motion: Some(Motion::Wave),
+                    ..current
+                }),
+                "shake" => stack.push(Style {
+                    motion: Some(Motion::Shake),
+                    ..current
+                }),
+                _ => {
+                    if let Some(duration) = tag.strip_prefix("pause:") {
+                        let Ok(duration) = duration.parse::<u32>() else {
+                            return Err(MarkupError::InvalidTag(tag.to_string()));
+                        };
+                        pauses.push((text.len(), duration as f32));
+                        continue;
+                    }
+                    let Some((_, color)) = TEXT_COLORS.iter().find(|f| f.0 == tag) else {
+                        return Err(MarkupError::InvalidTag(tag.to_string()));
+                    };
+                    stack.push(Style {
+                        color: Some(*color),
+                        ..current
+                    });
+                }
+            }
+
+            let style = *stack.last().unwrap();
+            let last = styles.last_mut().unwrap();
+            if last.0 == text.len() {
+                last.1 = style;
+            } else if last.1 != style {
+                styles.push((text.len(), style));
+            }
+        }
+        text.push_str(rest);
+        Ok(Self {
+            text,
+            styles,
+            pauses,
+        })
+    }
+    /// Style of the character at `index`, in bytes
+    pub fn style_at(&self, index: usize) -> Style {
+        self.styles[self.styles.partition_point(|f| f.0 <= index) - 1].1
+    }
+}
+
+/// Reveals text a character at a time, as if it's being typed
+pub struct Typewriter {
+    /// Milliseconds between characters
+    char_delay: f32,
+    /// How much of the text is revealed, in bytes
+    revealed: usize,
+    /// Milliseconds until the next character
+    wait: f32,
+    /// Whether the `{pause}` before the next character has been waited for
+    paused: bool,
+}
+impl Default for Typewriter {
+    fn default() -> Self {
+        Self::new(35.0)
+    }
+}
+impl Typewriter {
+    pub fn new(char_delay: f32) -> Self {
+        Self {
+            char_delay,
+            revealed: 0,
+            wait: 0.0,
+            paused: false,
+        }
+    }
+    /// Extra milliseconds to wait after punctuation, so sentences don't run together
+    fn punctuation_pause(char: char) -> f32 {
+        match char {
+            '.' | '!' | '?' => 250.0,
+            ',' | ';' | ':' => 120.0,
+            _ => 0.0,
+        }
+    }
+    /// Reveals characters for `delta` more milliseconds, calling `on_reveal` with each one
+    /// that isn't a space
+    pub fn update(&mut self, text: &RichText, delta: f32, mut on_reveal: impl FnMut(char)) {
+        self.wait -= delta;
+        while self.wait <= 0.0 && !self.is_finished(text) {
+            if !self.paused {
+                self.paused = true;
+                self.wait += text
+                    .pauses
+                    .iter()
+                    .filter(|f| f.0 == self.revealed)
+                    .map(|f| f.1)
+                    .sum::<f32>();
+                continue;
+            }
+            let char = text.text[self.revealed..].chars().next().unwrap();
+            self.revealed += char.len_utf8();
+            self.paused = false;
+            self.wait += self.char_delay + Self::punctuation_pause(char);
+            if !char.is_whitespace() {
+                on_reveal(char);
+            }
+        }
+        if self.is_finished(text) {
+            self.wait = 0.0;
+        }
+    }
+    /// Reveals the rest of the text at once
+    pub fn skip(&mut self, text: &RichText) {
+        self.revealed = text.text.len();
+    }
+    /// Hides the text again, to type it out from the start
+    pub fn reset(&mut self) {
+        *self = Self::new(self.char_delay);
+    }
+    pub fn is_finished(&self, text: &RichText) -> bool {
+        self.revealed >= text.text.len()
+    }
+    /// How much of the text is revealed, in bytes
+    pub fn revealed(&self) -> usize {
+        self.revealed
+    }
+}
+
+/// Parses markup, or shows it as it's written (and says why) if it's invalid,
+/// so a typo in a language file doesn't stop the game
+pub fn markup(markup: &str) -> RichText {
+    RichText::parse(markup).unwrap_or_else(|err| {
+        eprintln!("bad markup in {markup:?}: {err}");
+        RichText {
+            text: markup.to_string(),
+            styles: vec![(0, Style::default())],
+            pauses: Vec::new(),
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum MarkupError {
+    /// A `{` without a `}` after it
+    Unclosed(String),
+    InvalidTag(String),
+    /// A `{/}` without a style to end
+    NothingToEnd,
+}
+impl fmt::Display for MarkupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarkupError::Unclosed(rest) => write!(f, "unclosed brace before {rest:?}"),
+            MarkupError::InvalidTag(tag) => write!(f, "unknown markup {{{tag}}}"),
+            MarkupError::NothingToEnd => write!(f, "{{/}} without anything to end"),
+        }
+    }
+}
+impl std::error::Error for MarkupError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Style {
+        Style {
+            color: Some(Color::from_hex(0xda2424).to_vec()),
+            motion: None,
+        }
+    }
+
+    #[test]
+    fn parse_plain_text() {
+        let text = RichText::parse("hello there").unwrap();
+        assert_eq!(text.text, "hello there");
+        assert!(text.style_at(0) == Style::default());
+        assert!(text.pauses.is_empty());
+    }
+
+    #[test]
+    fn parse_escaped_braces() {
+        assert_eq!(RichText::parse("a{{b").unwrap().text, "a{b");
+        // the escaped brace doesn't start a tag, and a closing brace on its own is just text
+        let text = RichText::parse("{{red} {red}x{/}").unwrap();
+        assert_eq!(text.text, "{red} x");
+        assert!(text.style_at(0) == Style::default());
+        assert!(text.style_at(6) == red());
+        assert_eq!(RichText::parse("a}b").unwrap().text, "a}b");
+    }
+
+    #[test]
+    fn parse_unclosed_brace() {
+        assert!(matches!(
+            RichText::parse("a{red"),
+            Err(MarkupError::Unclosed(rest)) if rest == "red"
+        ));
+        assert!(matches!(
+            RichText::parse("a{"),
+            Err(MarkupError::Unclosed(rest)) if rest.is_empty()
+        ));
+    }
+
+    #[test]
+    fn parse_stray_end() {
+        assert!(matches!(
+            RichText::parse("a{/}b"),
+            Err(MarkupError::NothingToEnd)
+        ));
+        assert!(matches!(
+            RichText::parse("{red}a{/}{/}"),
+            Err(MarkupError::NothingToEnd)
+        ));
+    }
+
+    #[test]
+    fn parse_unknown_tags() {
+        assert!(matches!(
+            RichText::parse("{bold}a"),
+            Err(MarkupError::InvalidTag(tag)) if tag == "bold"
+        ));
+        for markup in ["{pause:x}", "{pause:}", "{pause:-1}"] {
+            assert!(matches!(
+                RichText::parse(markup),
+                Err(MarkupError::InvalidTag(tag)) if markup == format!("{{{tag}}}")
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_pauses() {
+        let text = RichText::parse("ab{pause:300}c{pause:50}").unwrap();
+        assert_eq!(text.text, "abc");
+        assert_eq!(text.pauses, [(2, 300.0), (3, 50.0)]);
+        // pauses don't change the style
+        assert!(text.style_at(2) == Style::default());
+    }
+
+    #[test]
+    fn style_at_span_boundaries() {
+        let text = RichText::parse("a{red}bc{/}d").unwrap();
+        assert_eq!(text.text, "abcd");
+        let styles: Vec<_> = (0..4).map(|f| text.style_at(f)).collect();
+        assert!(styles == [Style::default(), red(), red(), Style::default()]);
+        // past the end is the style the text ends with
+        assert!(text.style_at(4) == Style::default());
+
+        let text = RichText::parse("{red}a{wave}b{/}c{/}d").unwrap();
+        let wave = Style {
+            motion: Some(Motion::Wave),
+            ..red()
+        };
+        let styles: Vec<_> = (0..4).map(|f| text.style_at(f)).collect();
+        assert!(styles == [red(), wave, red(), Style::default()]);
+    }
+
+    #[test]
+    fn style_at_empty_and_multibyte_spans() {
+        // a style that's ended straight away doesn't apply to anything
+        let text = RichText::parse("{red}{/}a").unwrap();
+        assert!(text.style_at(0) == Style::default());
+        assert_eq!(text.styles.len(), 1);
+
+        // indices are in bytes, and å is two of them
+        let text = RichText::parse("\u{e5}{red}\u{e4}").unwrap();
+        assert!(text.style_at(0) == Style::default());
+        assert!(text.style_at(1) == Style::default());
+        assert!(text.style_at(2) == red());
+    }
+
+    #[test]
+    fn bad_markup_is_shown_as_written() {
+        let text = markup("oops {red");
+        assert_eq!(text.text, "oops {red");
+        assert!(text.style_at(5) == Style::default());
+    }
+}
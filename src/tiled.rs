@@ -0,0 +1,900 @@
+use std::{collections::HashMap, fmt, io::Read, str::FromStr};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use roxmltree::{Document, Node};
+use ruzstd::decoding::StreamingDecoder;
+
+#[derive(Debug)]
+pub enum MapError {
+    Xml(roxmltree::Error),
+    UnexpectedElement {
+        line: u32,
+        expected: &'static str,
+        found: String,
+    },
+    MissingAttribute {
+        line: u32,
+        element: String,
+        attribute: &'static str,
+    },
+    InvalidAttribute {
+        line: u32,
+        element: String,
+        attribute: &'static str,
+        value: String,
+    },
+    InvalidData {
+        line: u32,
+        message: String,
+    },
+    Unsupported {
+        line: u32,
+        message: String,
+    },
+    MissingMap(String),
+    MissingLayer(String),
+    MissingTileset(String),
+    MissingImage(String),
+    InvalidImage {
+        source: String,
+        reason: String,
+    },
+    /// A tile that isn't in the first tileset, which can't be drawn
+    InvalidTile {
+        layer: String,
+        gid: u32,
+    },
+    InTileset {
+        source: String,
+        error: Box<MapError>,
+    },
+    WrongLayerKind {
+        name: String,
+        expected: &'static str,
+    },
+    WrongTileSize {
+        width: u32,
+        height: u32,
+    },
+}
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Xml(err) => write!(f, "malformed xml: {err}"),
+            MapError::UnexpectedElement {
+                line,
+                expected,
+                found,
+            } => write!(f, "line {line}: expected <{expected}>, found <{found}>"),
+            MapError::MissingAttribute {
+                line,
+                element,
+                attribute,
+            } => write!(
+                f,
+                "line {line}: <{element}> is missing attribute `{attribute}`"
+            ),
+            MapError::InvalidAttribute {
+                line,
+                element,
+                attribute,
+                value,
+            } => write!(
+                f,
+                "line {line}: <{element}> has invalid value {value:?} for attribute `{attribute}`"
+            ),
+            MapError::InvalidData { line, message } => write!(f, "line {line}: {message}"),
+            MapError::Unsupported { line, message } => {
+                write!(f, "line {line}: unsupported {message}")
+            }
+            MapError::MissingMap(name) => write!(f, "no map named {name:?}"),
+            MapError::MissingLayer(name) => write!(f, "map has no layer named {name:?}"),
+            MapError::MissingTileset(source) => write!(f, "tileset {source:?} not found"),
+            MapError::MissingImage(source) => write!(f, "image {source:?} not found"),
+            MapError::InvalidImage { source, reason } => {
+                write!(f, "image {source:?} can't be read: {reason}")
+            }
+            MapError::InvalidTile { layer, gid } => {
+                write!(
+                    f,
+                    "layer {layer:?} has tile {gid}, which isn't in the tileset"
+                )
+            }
+            MapError::InTileset { source, error } => write!(f, "in tileset {source:?}: {error}"),
+            MapError::WrongLayerKind { name, expected } => {
+                write!(f, "layer {name:?} is not a {expected} layer")
+            }
+            MapError::WrongTileSize { width, height } => {
+                write!(f, "map uses {width}x{height} tiles, expected 8x8")
+            }
+        }
+    }
+}
+impl std::error::Error for MapError {}
+impl From<roxmltree::Error> for MapError {
+    fn from(value: roxmltree::Error) -> Self {
+        MapError::Xml(value)
+    }
+}
+
+/// A parsed `.tmx` map, as saved by Tiled
+pub struct Map {
+    pub width: u32,
+    pub height: u32,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub infinite: bool,
+    /// Point where layers with parallax line up with the rest of the map, in pixels
+    pub parallax_origin_x: f32,
+    pub parallax_origin_y: f32,
+    pub tilesets: Vec<TilesetRef>,
+    /// All layers in the order they are drawn. Group layers are flattened.
+    pub layers: Vec<Layer>,
+}
+impl Map {
+    /// `load_tileset` is given the `source` of each external tileset and returns its contents
+    pub fn parse(
+        xml: &str,
+        load_tileset: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, MapError> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root_element();
+        if !root.has_tag_name("map") {
+            return Err(MapError::UnexpectedElement {
+                line: line_of(root),
+                expected: "map",
+                found: root.tag_name().name().to_string(),
+            });
+        }
+        let orientation: String = attr_or(root, "orientation", String::from("orthogonal"))?;
+        if orientation != "orthogonal" {
+            return Err(MapError::Unsupported {
+                line: line_of(root),
+                message: format!("map orientation {orientation:?}"),
+            });
+        }
+
+        let mut map = Map {
+            width: attr(root, "width")?,
+            height: attr(root, "height")?,
+            tile_width: attr(root, "tilewidth")?,
+            tile_height: attr(root, "tileheight")?,
+            infinite: attr_or::<u8>(root, "infinite", 0)? != 0,
+            parallax_origin_x: attr_or(root, "parallaxoriginx", 0.0)?,
+            parallax_origin_y: attr_or(root, "parallaxoriginy", 0.0)?,
+            tilesets: Vec::new(),
+            layers: Vec::new(),
+        };
+
+        for node in root.children().filter(Node::is_element) {
+            if !node.has_tag_name("tileset") {
+                continue;
+            }
+            let tileset = match node.attribute("source") {
+                Some(source) => {
+                    let xml = load_tileset(source)
+                        .ok_or_else(|| MapError::MissingTileset(source.to_string()))?;
+                    Tileset::parse(&xml).map_err(|err| MapError::InTileset {
+                        source: source.to_string(),
+                        error: Box::new(err),
+                    })?
+                }
+                None => Tileset::from_node(node)?,
+            };
+            map.tilesets.push(TilesetRef {
+                first_gid: attr(node, "firstgid")?,
+                tileset,
+            });
+        }
+        let parent = LayerInfo {
+            visible: true,
+            opacity: 1.0,
+            offset_x: 0.0,
+            offset_y: 0.0,
+            parallax_x: 1.0,
+            parallax_y: 1.0,
+            tint: [1.0; 4],
+        };
+        map.parse_layers(root, &parent)?;
+        Ok(map)
+    }
+    fn parse_layers(&mut self, parent_node: Node, parent: &LayerInfo) -> Result<(), MapError> {
+        for node in parent_node.children().filter(Node::is_element) {
+            let name = node.tag_name().name();
+            if !matches!(name, "layer" | "objectgroup" | "imagelayer" | "group") {
+                continue;
+            }
+            let tint = match node.attribute("tintcolor") {
+                Some(value) => parse_color(value).ok_or_else(|| MapError::InvalidAttribute {
+                    line: line_of(node),
+                    element: name.to_string(),
+                    attribute: "tintcolor",
+                    value: value.to_string(),
+                })?,
+                None => [1.0; 4],
+            };
+            // group layers multiply their properties onto their children
+            let info = LayerInfo {
+                visible: parent.visible && attr_or::<u8>(node, "visible", 1)? != 0,
+                opacity: parent.opacity * attr_or(node, "opacity", 1.0)?,
+                offset_x: parent.offset_x + attr_or(node, "offsetx", 0.0)?,
+                offset_y: parent.offset_y + attr_or(node, "offsety", 0.0)?,
+                parallax_x: parent.parallax_x * attr_or(node, "parallaxx", 1.0)?,
+                parallax_y: parent.parallax_y * attr_or(node, "parallaxy", 1.0)?,
+                tint: std::array::from_fn(|i| parent.tint[i] * tint[i]),
+            };
+            if name == "group" {
+                self.parse_layers(node, &info)?;
+                continue;
+            }
+            let id = attr(node, "id")?;
+            if self.layers.iter().any(|f| f.id == id) {
+                return Err(MapError::InvalidData {
+                    line: line_of(node),
+                    message: format!("duplicate layer id {id}"),
+                });
+            }
+            let kind = match name {
+                "objectgroup" => LayerKind::Objects(parse_objects(node)?),
+                "imagelayer" => LayerKind::Image(parse_image_layer(node)?),
+                _ => LayerKind::Tiles(self.parse_tile_data(node)?),
+            };
+            self.layers.push(Layer {
+                id,
+                name: attr_or(node, "name", String::new())?,
+                visible: info.visible,
+                opacity: info.opacity,
+                offset_x: info.offset_x,
+                offset_y: info.offset_y,
+                parallax_x: info.parallax_x,
+                parallax_y: info.parallax_y,
+                tint: info.tint,
+                properties: parse_properties(node)?,
+                kind,
+            });
+        }
+        Ok(())
+    }
+    fn parse_tile_data(&self, layer: Node) -> Result<Vec<TileChunk>, MapError> {
+        let Some(data) = layer.children().find(|f| f.has_tag_name("data")) else {
+            return Ok(Vec::new());
+        };
+        let encoding = Encoding::from_data(data)?;
+
+        if !self.infinite {
+            // finite maps store the whole layer as a single block of data
+            let width = attr_or(layer, "width", self.width)?;
+            let height = attr_or(layer, "height", self.height)?;
+            let tiles = parse_tiles(data, &encoding, width * height)?;
+            return Ok(vec![TileChunk {
+                x: 0,
+                y: 0,
+                width,
+                height,
+                tiles,
+            }]);
+        }
+
+        let mut chunks = Vec::new();
+        for node in data.children().filter(|f| f.has_tag_name("chunk")) {
+            let width = attr(node, "width")?;
+            let height = attr(node, "height")?;
+            chunks.push(TileChunk {
+                x: attr(node, "x")?,
+                y: attr(node, "y")?,
+                width,
+                height,
+                tiles: parse_tiles(node, &encoding, width * height)?,
+            });
+        }
+        Ok(chunks)
+    }
+}
+
+pub struct TilesetRef {
+    pub first_gid: u32,
+    pub tileset: Tileset,
+}
+
+/// A parsed `.tsx` tileset, or one embedded in a map
+pub struct Tileset {
+    pub tile_count: u32,
+    /// Tiles that have any extra data set in tiled, by their local id
+    pub tiles: HashMap<u32, TileData>,
+}
+impl Tileset {
+    pub fn parse(xml: &str) -> Result<Self, MapError> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root_element();
+        if !root.has_tag_name("tileset") {
+            return Err(MapError::UnexpectedElement {
+                line: line_of(root),
+                expected: "tileset",
+                found: root.tag_name().name().to_string(),
+            });
+        }
+        Self::from_node(root)
+    }
+    fn from_node(node: Node) -> Result<Self, MapError> {
+        let mut tiles = HashMap::new();
+        for tile in node.children().filter(|f| f.has_tag_name("tile")) {
+            let kind = match tile.attribute("type") {
+                Some(kind) => kind.to_string(),
+                None => attr_or(tile, "class", String::new())?,
+            };
+            let collision = match tile.children().find(|f| f.has_tag_name("objectgroup")) {
+                Some(group) => parse_objects(group)?,
+                None => Vec::new(),
+            };
+            let mut animation = Vec::new();
+            if let Some(frames) = tile.children().find(|f| f.has_tag_name("animation")) {
+                for frame in frames.children().filter(|f| f.has_tag_name("frame")) {
+                    animation.push(Frame {
+                        tile_id: attr(frame, "tileid")?,
+                        duration: attr(frame, "duration")?,
+                    });
+                }
+            }
+            tiles.insert(
+                attr(tile, "id")?,
+                TileData {
+                    kind,
+                    properties: parse_properties(tile)?,
+                    collision,
+                    animation,
+                },
+            );
+        }
+        Ok(Tileset {
+            tile_count: attr(node, "tilecount")?,
+            tiles,
+        })
+    }
+}
+
+pub struct TileData {
+    /// What tiled calls the tile's "type" (or "class" in tiled 1.9)
+    pub kind: String,
+    pub properties: Properties,
+    /// Collision shapes drawn in tiled's tile collision editor
+    pub collision: Vec<Object>,
+    pub animation: Vec<Frame>,
+}
+
+pub struct Frame {
+    /// Local id of the tile to show
+    pub tile_id: u32,
+    /// In milliseconds
+    pub duration: u32,
+}
+
+struct LayerInfo {
+    visible: bool,
+    opacity: f32,
+    offset_x: f32,
+    offset_y: f32,
+    parallax_x: f32,
+    parallax_y: f32,
+    tint: [f32; 4],
+}
+
+pub struct Layer {
+    pub id: u32,
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// How fast the layer scrolls compared to the camera. 1 scrolls normally,
+    /// 0 stays in place on screen
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    /// Color the layer is multiplied with, as rgba from 0 to 1
+    pub tint: [f32; 4],
+    pub properties: Properties,
+    pub kind: LayerKind,
+}
+
+pub enum LayerKind {
+    Tiles(Vec<TileChunk>),
+    Objects(Vec<Object>),
+    Image(ImageLayer),
+}
+
+/// A single image, optionally repeated to fill the view
+pub struct ImageLayer {
+    /// Path of the image, relative to the map. Empty if the layer has no image.
+    pub source: String,
+    pub repeat_x: bool,
+    pub repeat_y: bool,
+}
+
+fn parse_image_layer(layer: Node) -> Result<ImageLayer, MapError> {
+    let source = match layer.children().find(|f| f.has_tag_name("image")) {
+        Some(image) => attr_or(image, "source", String::new())?,
+        None => String::new(),
+    };
+    Ok(ImageLayer {
+        source,
+        repeat_x: attr_or::<u8>(layer, "repeatx", 0)? != 0,
+        repeat_y: attr_or::<u8>(layer, "repeaty", 0)? != 0,
+    })
+}
+
+/// Parses a tiled color, which is `#rrggbb` or `#aarrggbb`
+fn parse_color(value: &str) -> Option<[f32; 4]> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    let argb = match hex.len() {
+        6 => 0xff000000 | u32::from_str_radix(hex, 16).ok()?,
+        8 => u32::from_str_radix(hex, 16).ok()?,
+        _ => return None,
+    };
+    let channel = |shift: u32| ((argb >> shift) & 0xff) as f32 / 255.0;
+    Some([channel(16), channel(8), channel(0), channel(24)])
+}
+
+/// A rectangle of tile gids. Positions and sizes are in tiles.
+pub struct TileChunk {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub tiles: Vec<Gid>,
+}
+
+/// A global tile id, with tiled's flip flags stored in the top bits
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Gid(u32);
+impl Gid {
+    const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+    const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+    const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+    /// Only meaningful for hexagonal maps, but still has to be masked out
+    const ROTATED_HEXAGONAL_120: u32 = 0x1000_0000;
+    const FLAGS: u32 = Self::FLIPPED_HORIZONTALLY
+        | Self::FLIPPED_VERTICALLY
+        | Self::FLIPPED_DIAGONALLY
+        | Self::ROTATED_HEXAGONAL_120;
+
+    pub const EMPTY: Gid = Gid(0);
+
+    pub fn id(self) -> u32 {
+        self.0 & !Self::FLAGS
+    }
+    pub fn is_empty(self) -> bool {
+        self.id() == 0
+    }
+    /// Same flip flags, different tile
+    pub fn with_id(self, id: u32) -> Self {
+        Gid((self.0 & Self::FLAGS) | (id & !Self::FLAGS))
+    }
+    pub fn flipped_horizontally(self) -> bool {
+        self.0 & Self::FLIPPED_HORIZONTALLY != 0
+    }
+    pub fn flipped_vertically(self) -> bool {
+        self.0 & Self::FLIPPED_VERTICALLY != 0
+    }
+    pub fn flipped_diagonally(self) -> bool {
+        self.0 & Self::FLIPPED_DIAGONALLY != 0
+    }
+}
+
+/// An object from an object layer. Positions are in pixels.
+#[derive(Clone)]
+pub struct Object {
+    /// Unique within the map
+    pub id: u32,
+    pub name: String,
+    /// What tiled calls the object's "type" (or "class" in tiled 1.9)
+    pub kind: String,
+    pub x: f32,
+    pub y: f32,
+    /// Rotation in degrees, clockwise around (`x`, `y`)
+    pub rotation: f32,
+    pub shape: Shape,
+    pub properties: Properties,
+}
+impl Object {
+    /// Whether a point is inside the object. Points and polylines have no area.
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        // move the point into the object's unrotated local space
+        let (sin, cos) = (-self.rotation.to_radians()).sin_cos();
+        let (dx, dy) = (x - self.x, y - self.y);
+        let (x, y) = (dx * cos - dy * sin, dx * sin + dy * cos);
+
+        match &self.shape {
+            Shape::Point | Shape::Polyline => false,
+            Shape::Rectangle { width, height } => {
+                (0.0..*width).contains(&x) && (0.0..*height).contains(&y)
+            }
+            Shape::Ellipse { width, height } => {
+                let nx = (x - width / 2.0) / (width / 2.0);
+                let ny = (y - height / 2.0) / (height / 2.0);
+                nx * nx + ny * ny <= 1.0
+            }
+            Shape::Polygon(points) => {
+                // even-odd rule
+                let mut inside = false;
+                let mut j = points.len().wrapping_sub(1);
+                for (i, a) in points.iter().enumerate() {
+                    let b = points[j];
+                    if (a.1 > y) != (b.1 > y) && x < (b.0 - a.0) * (y - a.1) / (b.1 - a.1) + a.0 {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Shape {
+    Point,
+    Rectangle {
+        width: f32,
+        height: f32,
+    },
+    Ellipse {
+        width: f32,
+        height: f32,
+    },
+    /// Points are relative to the object's position
+    Polygon(Vec<(f32, f32)>),
+    /// Has no area, so the points aren't kept
+    Polyline,
+}
+
+/// Custom properties set in tiled, kept as their raw string values
+#[derive(Clone, Default)]
+pub struct Properties(HashMap<String, String>);
+impl Properties {
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.0.get(name)?.parse().ok()
+    }
+}
+
+fn parse_properties(node: Node) -> Result<Properties, MapError> {
+    let mut properties = HashMap::new();
+    let Some(list) = node.children().find(|f| f.has_tag_name("properties")) else {
+        return Ok(Properties::default());
+    };
+    for property in list.children().filter(|f| f.has_tag_name("property")) {
+        let name: String = attr(property, "name")?;
+        // multiline strings are stored as text instead of in `value`
+        let value = match property.attribute("value") {
+            Some(value) => value.to_string(),
+            None => property.text().unwrap_or_default().to_string(),
+        };
+        properties.insert(name, value);
+    }
+    Ok(Properties(properties))
+}
+
+fn parse_objects(layer: Node) -> Result<Vec<Object>, MapError> {
+    let mut objects = Vec::new();
+    for node in layer.children().filter(|f| f.has_tag_name("object")) {
+        if node.attribute("template").is_some() {
+            return Err(MapError::Unsupported {
+                line: line_of(node),
+                message: String::from("object templates"),
+            });
+        }
+        let width = attr_or(node, "width", 0.0)?;
+        let height = attr_or(node, "height", 0.0)?;
+        let mut shape = Shape::Rectangle { width, height };
+        for child in node.children().filter(Node::is_element) {
+            shape = match child.tag_name().name() {
+                "point" => Shape::Point,
+                "ellipse" => Shape::Ellipse { width, height },
+                "polygon" => Shape::Polygon(parse_points(child)?),
+                "polyline" => Shape::Polyline,
+                _ => continue,
+            };
+        }
+        let kind = match node.attribute("type") {
+            Some(kind) => kind.to_string(),
+            None => attr_or(node, "class", String::new())?,
+        };
+        objects.push(Object {
+            id: attr(node, "id")?,
+            name: attr_or(node, "name", String::new())?,
+            kind,
+            x: attr(node, "x")?,
+            y: attr(node, "y")?,
+            rotation: attr_or(node, "rotation", 0.0)?,
+            shape,
+            properties: parse_properties(node)?,
+        });
+    }
+    Ok(objects)
+}
+
+fn parse_points(node: Node) -> Result<Vec<(f32, f32)>, MapError> {
+    let points: String = attr(node, "points")?;
+    points
+        .split_whitespace()
+        .map(|point| {
+            let (x, y) = point.split_once(',')?;
+            Some((x.parse().ok()?, y.parse().ok()?))
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(|| MapError::InvalidAttribute {
+            line: line_of(node),
+            element: node.tag_name().name().to_string(),
+            attribute: "points",
+            value: points.clone(),
+        })
+}
+
+enum Encoding {
+    /// the old xml format, one <tile> per cell
+    Xml,
+    Csv,
+    Base64(Compression),
+}
+enum Compression {
+    None,
+    Zlib,
+    Gzip,
+    Zstd,
+}
+impl Encoding {
+    fn from_data(data: Node) -> Result<Self, MapError> {
+        let encoding: Option<String> = attr_opt(data, "encoding")?;
+        let compression: Option<String> = attr_opt(data, "compression")?;
+        let unsupported = |message| MapError::Unsupported {
+            line: line_of(data),
+            message,
+        };
+        match (encoding.as_deref(), compression.as_deref()) {
+            (None, None) => Ok(Encoding::Xml),
+            (Some("csv"), None) => Ok(Encoding::Csv),
+            (Some("base64"), None) => Ok(Encoding::Base64(Compression::None)),
+            (Some("base64"), Some("zlib")) => Ok(Encoding::Base64(Compression::Zlib)),
+            (Some("base64"), Some("gzip")) => Ok(Encoding::Base64(Compression::Gzip)),
+            (Some("base64"), Some("zstd")) => Ok(Encoding::Base64(Compression::Zstd)),
+            (_, Some(other)) => Err(unsupported(format!("tile data compression {other:?}"))),
+            (Some(other), None) => Err(unsupported(format!("tile data encoding {other:?}"))),
+        }
+    }
+}
+
+fn parse_tiles(node: Node, encoding: &Encoding, count: u32) -> Result<Vec<Gid>, MapError> {
+    let invalid = |message| MapError::InvalidData {
+        line: line_of(node),
+        message,
+    };
+    let tiles = match encoding {
+        Encoding::Xml => node
+            .children()
+            .filter(|f| f.has_tag_name("tile"))
+            .map(|f| attr_or(f, "gid", 0).map(Gid))
+            .collect::<Result<_, _>>()?,
+        Encoding::Csv => {
+            let text = node.text().unwrap_or_default();
+            let mut tiles = Vec::with_capacity(count as usize);
+            for item in text.split(',') {
+                let item = item.trim();
+                if item.is_empty() {
+                    continue;
+                }
+                tiles.push(Gid(item
+                    .parse()
+                    .map_err(|_| invalid(format!("invalid tile gid {item:?}")))?));
+            }
+            tiles
+        }
+        Encoding::Base64(compression) => {
+            let text: String = node.text().unwrap_or_default().split_whitespace().collect();
+            let bytes = BASE64
+                .decode(text)
+                .map_err(|err| invalid(format!("invalid base64 tile data: {err}")))?;
+            let bytes = decompress(&bytes, compression)
+                .map_err(|err| invalid(format!("failed to decompress tile data: {err}")))?;
+            if bytes.len() % 4 != 0 {
+                return Err(invalid(format!(
+                    "tile data is {} bytes, which is not a multiple of 4",
+                    bytes.len()
+                )));
+            }
+            bytes
+                .chunks_exact(4)
+                .map(|f| Gid(u32::from_le_bytes([f[0], f[1], f[2], f[3]])))
+                .collect()
+        }
+    };
+    if tiles.len() != count as usize {
+        return Err(invalid(format!(
+            "expected {count} tiles, found {}",
+            tiles.len()
+        )));
+    }
+    Ok(tiles)
+}
+
+fn decompress(bytes: &[u8], compression: &Compression) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    match compression {
+        Compression::None => return Ok(bytes.to_vec()),
+        Compression::Zlib => ZlibDecoder::new(bytes)
+            .read_to_end(&mut out)
+            .map_err(|err| err.to_string())?,
+        Compression::Gzip => GzDecoder::new(bytes)
+            .read_to_end(&mut out)
+            .map_err(|err| err.to_string())?,
+        Compression::Zstd => StreamingDecoder::new(bytes)
+            .map_err(|err| err.to_string())?
+            .read_to_end(&mut out)
+            .map_err(|err| err.to_string())?,
+    };
+    Ok(out)
+}
+
+fn line_of(node: Node) -> u32 {
+    node.document().text_pos_at(node.range().start).row
+}
+
+fn attr_opt<T: FromStr>(node: Node, attribute: &'static str) -> Result<Option<T>, MapError> {
+    let Some(value) = node.attribute(attribute) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| MapError::InvalidAttribute {
+            line: line_of(node),
+            element: node.tag_name().name().to_string(),
+            attribute,
+            value: value.to_string(),
+        })
+}
+
+fn attr_or<T: FromStr>(node: Node, attribute: &'static str, default: T) -> Result<T, MapError> {
+    Ok(attr_opt(node, attribute)?.unwrap_or(default))
+}
+
+fn attr<T: FromStr>(node: Node, attribute: &'static str) -> Result<T, MapError> {
+    attr_opt(node, attribute)?.ok_or_else(|| MapError::MissingAttribute {
+        line: line_of(node),
+        element: node.tag_name().name().to_string(),
+        attribute,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 map with one tile layer, whose `<data>` is on line 3
+    fn map(layer: &str, data: &str) -> Result<Map, MapError> {
+        let xml = format!(
+            r#"<map width="2" height="2" tilewidth="8" tileheight="8">
+ <layer id="1" name="tiles"{layer}>
+  <data{data}</data>
+ </layer>
+</map>"#
+        );
+        Map::parse(&xml, |_| None)
+    }
+    fn tiles(data: &str) -> Result<Vec<Gid>, MapError> {
+        let mut map = map("", data)?;
+        let LayerKind::Tiles(mut chunks) = map.layers.remove(0).kind else {
+            panic!("not a tile layer");
+        };
+        Ok(chunks.remove(0).tiles)
+    }
+
+    #[test]
+    fn tile_data_encodings() {
+        // 1, 2 flipped horizontally, 3, and an empty tile
+        let encodings = [
+            r#" encoding="csv">1,2147483650,
+3,0"#,
+            r#"><tile gid="1"/><tile gid="2147483650"/><tile gid="3"/><tile/>"#,
+            r#" encoding="base64">AQAAAAIAAIADAAAAAAAAAA=="#,
+            r#" encoding="base64" compression="zlib">eNpjZGBgYGJgaGBmgAAABNAAhw=="#,
+            r#" encoding="base64" compression="gzip">
+   H4sIAAAAAAACA2NkYGBgYmBoYGaAAAD3QzJGEAAAAA==
+  "#,
+            r#" encoding="base64" compression="zstd">KLUv/SAQgQAAAQAAAAIAAIADAAAAAAAAAA=="#,
+        ];
+        for data in encodings {
+            let tiles = tiles(data).unwrap_or_else(|err| panic!("{data}: {err}"));
+            assert_eq!(
+                tiles.iter().map(|f| f.id()).collect::<Vec<_>>(),
+                [1, 2, 3, 0]
+            );
+            let flipped: Vec<_> = tiles.iter().map(|f| f.flipped_horizontally()).collect();
+            assert_eq!(flipped, [false, true, false, false]);
+            assert!(!tiles[1].flipped_vertically() && !tiles[1].flipped_diagonally());
+        }
+    }
+
+    #[test]
+    fn wrong_tile_count() {
+        let short = tiles(r#" encoding="csv">1,2,3"#);
+        assert!(matches!(
+            short,
+            Err(MapError::InvalidData { line: 3, message }) if message == "expected 4 tiles, found 3"
+        ));
+        // a byte short of 4 tiles
+        let uneven = tiles(r#" encoding="base64">AQAAAAIAAIADAAAAAAAA"#);
+        assert!(matches!(uneven, Err(MapError::InvalidData { line: 3, .. })));
+    }
+
+    #[test]
+    fn bad_tile_data() {
+        let gid = tiles(r#" encoding="csv">1,x,3,0"#);
+        assert!(matches!(
+            gid,
+            Err(MapError::InvalidData { line: 3, message }) if message == r#"invalid tile gid "x""#
+        ));
+        let base64 = tiles(r#" encoding="base64">not base64!"#);
+        assert!(matches!(base64, Err(MapError::InvalidData { line: 3, .. })));
+        let zlib = tiles(r#" encoding="base64" compression="zlib">AQAAAAIAAIADAAAAAAAAAA=="#);
+        assert!(matches!(zlib, Err(MapError::InvalidData { line: 3, .. })));
+        let compression = tiles(r#" encoding="base64" compression="lzma">"#);
+        assert!(matches!(
+            compression,
+            Err(MapError::Unsupported { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn bad_attributes() {
+        let opacity = map(r#" opacity="lots""#, r#" encoding="csv">1,2,3,0"#);
+        assert!(matches!(
+            opacity,
+            Err(MapError::InvalidAttribute { line: 2, element, attribute: "opacity", value })
+                if element == "layer" && value == "lots"
+        ));
+        let tint = map(r##" tintcolor="#12""##, r#" encoding="csv">1,2,3,0"#);
+        assert!(matches!(
+            tint,
+            Err(MapError::InvalidAttribute {
+                line: 2,
+                attribute: "tintcolor",
+                ..
+            })
+        ));
+        let tile = tiles(r#"><tile gid="-1"/>"#);
+        assert!(matches!(
+            tile,
+            Err(MapError::InvalidAttribute {
+                line: 3,
+                attribute: "gid",
+                ..
+            })
+        ));
+        let missing = Map::parse(r#"<map height="2" tilewidth="8" tileheight="8"/>"#, |_| {
+            None
+        });
+        assert!(matches!(
+            missing,
+            Err(MapError::MissingAttribute {
+                line: 1,
+                attribute: "width",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn objects_keep_their_ids() {
+        // the same npc can be placed more than once, so ids tell them apart
+        let xml = r#"<map width="2" height="2" tilewidth="8" tileheight="8">
+ <objectgroup id="1" name="objects">
+  <object id="4" name="henry" type="npc" x="0" y="0"><point/></object>
+  <object id="7" name="henry" type="npc" x="8" y="0"><point/></object>
+ </objectgroup>
+</map>"#;
+        let map = Map::parse(xml, |_| None).unwrap();
+        let LayerKind::Objects(objects) = &map.layers[0].kind else {
+            panic!("not an object layer");
+        };
+        assert_eq!(objects.iter().map(|f| f.id).collect::<Vec<_>>(), [4, 7]);
+    }
+}
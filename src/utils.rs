@@ -1,5 +1,6 @@
 use std::sync::LazyLock;
 
+use crate::atlas::Sprite;
 use macroquad::{
     miniquad::{BlendFactor, BlendState, BlendValue, Equation},
     prelude::*,
@@ -12,6 +13,11 @@ pub const MAX_VELOCITY: f32 = 1.2;
 pub const GROUND_FRICTION: f32 = 0.21;
 pub const AIR_DRAG: f32 = 0.07;
 pub const GRAVITY: f32 = 0.9;
+pub const CLIMB_SPEED: f32 = 0.8;
+/// Multiplier for gravity while in water
+pub const WATER_GRAVITY: f32 = 0.2;
+pub const WATER_DRAG: f32 = 0.15;
+pub const SWIM_FORCE: f32 = 0.4;
 
 pub fn create_camera(w: f32, h: f32) -> Camera2D {
     let rt = render_target(w as u32, h as u32);
@@ -24,9 +30,20 @@ pub fn create_camera(w: f32, h: f32) -> Camera2D {
     }
 }
 
+/// The area of the world a camera made with `create_camera` can see
+pub fn view_rect(camera: &Camera2D) -> Rect {
+    let size = Vec2::new(2.0 / camera.zoom.x, 2.0 / camera.zoom.y);
+    Rect::new(
+        camera.target.x - size.x / 2.0,
+        camera.target.y - size.y / 2.0,
+        size.x,
+        size.y,
+    )
+}
+
 pub fn draw_button(
-    texture: &Texture2D,
-    texture_hovered: &Texture2D,
+    texture: &Sprite,
+    texture_hovered: &Sprite,
     x: f32,
     y: f32,
     mouse_x: f32,
@@ -40,25 +57,76 @@ pub fn draw_button(
     let hovered =
         (x..x + texture.width()).contains(&mouse_x) && (y..y + texture.height()).contains(&mouse_y);
 
-    draw_texture_ex(
-        if hovered { texture_hovered } else { texture },
-        x,
-        y,
-        WHITE,
-        params,
-    );
+    let texture = if hovered { texture_hovered } else { texture };
+    texture.draw_ex(x, y, WHITE, params);
 
     hovered && is_mouse_button_pressed(MouseButton::Left)
 }
 
-pub static TEXT_COLORS: &[Vec4] = &[
-    Vec4::new(1.0, 1.0, 1.0, 1.0),
-    Vec4::new(0.0, 0.0, 0.0, 1.0),
-    Color::from_hex(0xda2424).to_vec(),
-    Color::from_hex(0x2890dc).to_vec(),
-    Color::from_hex(0x08b23b).to_vec(),
-    Color::from_hex(0x720d0d).to_vec(),
+/// Key for talking to npcs and taking things
+pub const INTERACT_KEY: KeyCode = KeyCode::E;
+
+/// Key for switching between the languages the game has, on the start screen
+pub const LANGUAGE_KEY: KeyCode = KeyCode::L;
+
+/// Name of a key as it's shown to the player, like `e`, `1` or `left shift`
+pub fn key_name(key: KeyCode) -> String {
+    let name = match key {
+        KeyCode::Apostrophe => "'",
+        KeyCode::Comma => ",",
+        KeyCode::Minus => "-",
+        KeyCode::Period => ".",
+        KeyCode::Slash => "/",
+        KeyCode::Semicolon => ";",
+        KeyCode::Equal => "=",
+        KeyCode::LeftBracket => "[",
+        KeyCode::Backslash => "\\",
+        KeyCode::RightBracket => "]",
+        KeyCode::GraveAccent => "`",
+        KeyCode::Escape => "esc",
+        KeyCode::Unknown => "?",
+        _ => "",
+    };
+    if !name.is_empty() {
+        return name.to_string();
+    }
+    // the rest are named like `Key1`, `Kp1`, `PageUp` and `LeftShift`
+    let debug = format!("{key:?}");
+    let debug = debug.strip_prefix("Key").unwrap_or(&debug);
+    let debug = debug.replace("Kp", "Keypad ");
+    let mut name = String::new();
+    for char in debug.chars() {
+        if char.is_uppercase() && !name.is_empty() && !name.ends_with(' ') {
+            name.push(' ');
+        }
+        name.extend(char.to_lowercase());
+    }
+    name
+}
+
+/// Colours text can be drawn in, by the name markup uses for them
+pub static TEXT_COLORS: &[(&str, Vec4)] = &[
+    ("white", Vec4::new(1.0, 1.0, 1.0, 1.0)),
+    ("black", Vec4::new(0.0, 0.0, 0.0, 1.0)),
+    ("red", Color::from_hex(0xda2424).to_vec()),
+    ("blue", Color::from_hex(0x2890dc).to_vec()),
+    ("green", Color::from_hex(0x08b23b).to_vec()),
+    ("dark_red", Color::from_hex(0x720d0d).to_vec()),
 ];
+/// Called with each character of someone's text as it's revealed, like to play a blip
+pub type Voice = fn(char);
+
+/// Voices text is spoken in, by the key of the speaker's name. Used for their text bubbles
+/// and dialogue lines. There aren't any yet, since the game has no sound.
+pub static VOICES: &[(&str, Voice)] = &[];
+
+/// The voice of the speaker whose name is at `key`, if they have one
+pub fn voice(key: &str) -> Option<Voice> {
+    VOICES.iter().find(|f| f.0 == key).map(|f| f.1)
+}
+
+/// Colour of text that doesn't say otherwise
+pub const TEXT_COLOR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
 
 pub static COLOR_MOD_MATERIAL: LazyLock<Material> = LazyLock::new(|| {
     // to enable transparency!
@@ -87,10 +155,96 @@ pub static COLOR_MOD_MATERIAL: LazyLock<Material> = LazyLock::new(|| {
         },
     )
     .unwrap();
-    m.set_uniform("color", TEXT_COLORS[0]);
+    m.set_uniform("color", TEXT_COLOR);
     m
 });
 
+/// Replaces colours with the ones at the same index in another palette, see `PaletteSwap`
+pub static PALETTE_SWAP_MATERIAL: LazyLock<Material> = LazyLock::new(|| {
+    let pipeline = PipelineParams {
+        color_blend: Some(BlendState::new(
+            Equation::Add,
+            BlendFactor::Value(BlendValue::SourceAlpha),
+            BlendFactor::OneMinusValue(BlendValue::SourceAlpha),
+        )),
+        ..Default::default()
+    };
+    load_material(
+        ShaderSource::Glsl {
+            vertex: DEFAULT_VERTEX_SHADER,
+            fragment: PALETTE_SWAP_FRAGMENT,
+        },
+        MaterialParams {
+            pipeline_params: pipeline,
+            uniforms: vec![UniformDesc::new("palette_size", UniformType::Float1)],
+            textures: vec![String::from("palette")],
+        },
+    )
+    .unwrap()
+});
+
+/// `palette` has the colours to replace on its top row, and what to replace them with below
+pub const PALETTE_SWAP_FRAGMENT: &str = "#version 100
+precision mediump float;
+
+varying vec2 uv;
+
+uniform sampler2D Texture;
+uniform sampler2D palette;
+uniform float palette_size;
+
+void main() {
+    vec4 color = texture2D(Texture, uv);
+    for (int i = 0; i < 256; i++) {
+        if (float(i) >= palette_size) {
+            break;
+        }
+        float x = (float(i) + 0.5) / palette_size;
+        if (distance(color, texture2D(palette, vec2(x, 0.25))) < 0.002) {
+            color = texture2D(palette, vec2(x, 0.75));
+            break;
+        }
+    }
+    gl_FragColor = color;
+}
+";
+
+/// Adds colors onto what's already drawn, for things that glow
+pub static ADDITIVE_MATERIAL: LazyLock<Material> = LazyLock::new(|| {
+    let pipeline = PipelineParams {
+        color_blend: Some(BlendState::new(
+            Equation::Add,
+            BlendFactor::Value(BlendValue::SourceAlpha),
+            BlendFactor::One,
+        )),
+        ..Default::default()
+    };
+    load_material(
+        ShaderSource::Glsl {
+            vertex: DEFAULT_VERTEX_SHADER,
+            fragment: DEFAULT_FRAGMENT_SHADER,
+        },
+        MaterialParams {
+            pipeline_params: pipeline,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+});
+
+pub const DEFAULT_FRAGMENT_SHADER: &str = "#version 100
+precision lowp float;
+
+varying vec2 uv;
+varying vec4 vertex_color;
+
+uniform sampler2D Texture;
+
+void main() {
+    gl_FragColor = texture2D(Texture, uv) * vertex_color;
+}
+";
+
 pub const COLOR_MOD_FRAGMENT: &str = "#version 100
 precision lowp float;
 
@@ -114,8 +268,11 @@ precision lowp float;
 
 attribute vec3 position;
 attribute vec2 texcoord;
+attribute vec4 color0;
 
 varying vec2 uv;
+// named so it doesn't clash with the `color` uniform of `COLOR_MOD_FRAGMENT`
+varying vec4 vertex_color;
 
 uniform mat4 Model;
 uniform mat4 Projection;
@@ -123,5 +280,29 @@ uniform mat4 Projection;
 void main() {
     gl_Position = Projection * Model * vec4(position, 1);
     uv = texcoord;
+    vertex_color = color0 / 255.0;
 }
 ";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        let names = [
+            (KeyCode::E, "e"),
+            (KeyCode::Key1, "1"),
+            (KeyCode::Kp1, "keypad 1"),
+            (KeyCode::KpEnter, "keypad enter"),
+            (KeyCode::LeftShift, "left shift"),
+            (KeyCode::PageUp, "page up"),
+            (KeyCode::F12, "f12"),
+            (KeyCode::Space, "space"),
+            (KeyCode::Comma, ","),
+        ];
+        for (key, name) in names {
+            assert_eq!(key_name(key), name);
+        }
+    }
+}